@@ -0,0 +1,152 @@
+//! Compares the single-pass StrictForm parser with the pipeline it replaced:
+//! scan for bad escapes, decode every pair into owned buffers, collect them
+//! into a `HashMap`, re-encode that and parse it again with
+//! `serde_urlencoded`.
+//!
+//! Run with `cargo bench --bench strict_form`.
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::hint::black_box;
+
+use incosense::strict_form::StrictFormLimits;
+use incosense::urlencoded::from_bytes;
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct Subscriber {
+    name: String,
+    email: String,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct Newsletter {
+    title: String,
+    html_content: String,
+    text_content: String,
+}
+
+fn subscribe_body() -> Vec<u8> {
+    b"name=le%20guin&email=ursula_le_guin%40gmail.com".to_vec()
+}
+
+/// A plain body with nothing to decode, where borrowing pays off most
+fn newsletter_body(size: usize) -> Vec<u8> {
+    let html = "a".repeat(size);
+    format!("title=Issue&html_content={html}&text_content={html}").into_bytes()
+}
+
+fn legacy<T: serde::de::DeserializeOwned>(body: &[u8]) -> Option<T> {
+    let whole = body.to_vec();
+    if legacy::percent_encoding_is_invalid(&whole) {
+        return None;
+    }
+    let mut form_map: HashMap<String, String> = HashMap::new();
+    for (k, v) in legacy::parse_raw_form(&whole) {
+        if k.contains(&0) || v.contains(&0) {
+            return None;
+        }
+        form_map.insert(String::from_utf8(k).ok()?, String::from_utf8(v).ok()?);
+    }
+    serde_urlencoded::from_str(&serde_urlencoded::to_string(&form_map).ok()?).ok()
+}
+
+fn bench_subscribe(c: &mut Criterion) {
+    let body = subscribe_body();
+    let limits = StrictFormLimits::default();
+    let mut group = c.benchmark_group("subscribe_form");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+    group.bench_function("legacy", |b| {
+        b.iter(|| legacy::<Subscriber>(black_box(&body)).unwrap())
+    });
+    group.bench_function("single_pass", |b| {
+        b.iter(|| from_bytes::<Subscriber>(black_box(&body), &limits).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_newsletter(c: &mut Criterion) {
+    let limits = StrictFormLimits {
+        max_body_bytes: usize::MAX,
+        max_value_len: usize::MAX,
+        ..StrictFormLimits::default()
+    };
+    let mut group = c.benchmark_group("newsletter_form");
+    for size in [1024, 64 * 1024, 1024 * 1024] {
+        let body = newsletter_body(size);
+        group.throughput(Throughput::Bytes(body.len() as u64));
+        group.bench_with_input(BenchmarkId::new("legacy", size), &body, |b, body| {
+            b.iter(|| legacy::<Newsletter>(black_box(body)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("single_pass", size), &body, |b, body| {
+            b.iter(|| from_bytes::<Newsletter>(black_box(body), &limits).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_subscribe, bench_newsletter);
+criterion_main!(benches);
+
+/// The parsing helpers as they were before the single-pass rewrite
+mod legacy {
+    pub fn percent_encoding_is_invalid(bytes: &[u8]) -> bool {
+        let mut i = 0usize;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'%' => {
+                    if i + 2 >= bytes.len() {
+                        return true;
+                    }
+                    if !bytes[i + 1].is_ascii_hexdigit() || !bytes[i + 2].is_ascii_hexdigit() {
+                        return true;
+                    }
+                    i += 3;
+                }
+                _ => i += 1,
+            }
+        }
+        false
+    }
+
+    pub fn parse_raw_form(data: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut out = Vec::new();
+        for pair in data.split(|&b| b == b'&') {
+            if pair.is_empty() {
+                continue;
+            }
+            match pair.iter().position(|&b| b == b'=') {
+                Some(idx) => out.push((
+                    percent_decode_bytes(&pair[..idx]),
+                    percent_decode_bytes(&pair[idx + 1..]),
+                )),
+                None => out.push((percent_decode_bytes(pair), Vec::new())),
+            }
+        }
+        out
+    }
+
+    fn percent_decode_bytes(input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len());
+        let mut i = 0;
+        while i < input.len() {
+            match input[i] {
+                b'+' => {
+                    out.push(b' ');
+                    i += 1;
+                }
+                b'%' if i + 2 < input.len() => {
+                    let hex = std::str::from_utf8(&input[i + 1..i + 3]).unwrap();
+                    out.push(u8::from_str_radix(hex, 16).unwrap());
+                    i += 3;
+                }
+                b => {
+                    out.push(b);
+                    i += 1;
+                }
+            }
+        }
+        out
+    }
+}
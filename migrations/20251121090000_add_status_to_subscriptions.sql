@@ -0,0 +1,6 @@
+-- Track where a subscriber is in the double opt-in flow.
+-- Rows created before this migration already received newsletters,
+-- so they are treated as confirmed.
+ALTER TABLE subscriptions ADD COLUMN status TEXT NULL;
+UPDATE subscriptions SET status = 'confirmed' WHERE status IS NULL;
+ALTER TABLE subscriptions ALTER COLUMN status SET NOT NULL;
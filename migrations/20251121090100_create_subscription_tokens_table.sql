@@ -0,0 +1,7 @@
+-- Confirmation tokens sent out in the double opt-in email
+CREATE TABLE subscription_tokens(
+  subscription_token TEXT NOT NULL,
+  subscriber_id uuid NOT NULL
+    REFERENCES subscriptions (id),
+  PRIMARY KEY (subscription_token)
+);
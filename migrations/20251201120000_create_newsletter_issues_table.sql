@@ -0,0 +1,9 @@
+-- Every newsletter issue published through POST /admin/newsletters
+CREATE TABLE newsletter_issues(
+  newsletter_issue_id uuid NOT NULL,
+  PRIMARY KEY (newsletter_issue_id),
+  title TEXT NOT NULL,
+  text_content TEXT NOT NULL,
+  html_content TEXT NOT NULL,
+  published_at timestamptz NOT NULL
+);
@@ -0,0 +1,11 @@
+-- One row per (issue, subscriber) pair still waiting to be delivered.
+-- Rows are deleted once the email went out or retries are exhausted.
+CREATE TABLE issue_delivery_queue(
+  newsletter_issue_id uuid NOT NULL
+    REFERENCES newsletter_issues (newsletter_issue_id),
+  subscriber_id uuid NOT NULL
+    REFERENCES subscriptions (id),
+  n_retries SMALLINT NOT NULL DEFAULT 0,
+  execute_after timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (newsletter_issue_id, subscriber_id)
+);
@@ -0,0 +1,14 @@
+-- Saved responses for requests carrying an Idempotency-Key header.
+-- A row without a response status is a request still in flight.
+CREATE TABLE idempotency(
+  user_id uuid NOT NULL,
+  idempotency_key TEXT NOT NULL,
+  request_method TEXT NOT NULL,
+  request_path TEXT NOT NULL,
+  response_status_code SMALLINT NULL,
+  response_header_names TEXT[] NULL,
+  response_header_values BYTEA[] NULL,
+  response_body BYTEA NULL,
+  created_at timestamptz NOT NULL,
+  PRIMARY KEY (user_id, idempotency_key)
+);
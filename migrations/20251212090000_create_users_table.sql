@@ -0,0 +1,7 @@
+-- Admin accounts. Passwords are stored as Argon2id PHC strings.
+CREATE TABLE users(
+  user_id uuid NOT NULL,
+  PRIMARY KEY (user_id),
+  username TEXT NOT NULL UNIQUE,
+  password_hash TEXT NOT NULL
+);
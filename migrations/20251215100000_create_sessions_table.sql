@@ -0,0 +1,11 @@
+-- Server-side admin sessions. Only a SHA-256 of the cookie value is
+-- stored, so a leaked table can't be replayed as cookies.
+CREATE TABLE sessions(
+  session_id_hash TEXT NOT NULL,
+  PRIMARY KEY (session_id_hash),
+  user_id uuid NOT NULL
+    REFERENCES users (user_id) ON DELETE CASCADE,
+  created_at timestamptz NOT NULL,
+  last_seen_at timestamptz NOT NULL
+);
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
@@ -0,0 +1,2 @@
+-- Where password reset links for an admin are sent
+ALTER TABLE users ADD COLUMN email TEXT NULL UNIQUE;
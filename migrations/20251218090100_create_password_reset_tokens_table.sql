@@ -0,0 +1,10 @@
+-- Single-use password reset tokens. Only a SHA-256 of the token is stored.
+CREATE TABLE password_reset_tokens(
+  token_hash TEXT NOT NULL,
+  PRIMARY KEY (token_hash),
+  user_id uuid NOT NULL
+    REFERENCES users (user_id) ON DELETE CASCADE,
+  created_at timestamptz NOT NULL,
+  expires_at timestamptz NOT NULL,
+  used_at timestamptz NULL
+);
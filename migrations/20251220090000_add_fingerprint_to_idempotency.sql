@@ -0,0 +1,5 @@
+-- Retries must repeat the original request exactly, body included.
+-- created_at doubles as the claim time: in-flight rows older than the claim
+-- timeout are presumed abandoned, and all rows expire after a day.
+ALTER TABLE idempotency ADD COLUMN request_fingerprint BYTEA NOT NULL DEFAULT ''::bytea;
+CREATE INDEX idempotency_created_at_idx ON idempotency (created_at);
@@ -0,0 +1,298 @@
+//! src/authentication.rs
+//! Admin credentials: Argon2id hashing and verification.
+//!
+//! Hashing is deliberately slow, so it always runs on the blocking thread
+//! pool. Verification does the same amount of work for unknown usernames
+//! as for known ones, so response times don't reveal which accounts exist.
+use std::sync::LazyLock;
+
+use anyhow::Context;
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::routes::{AppState, accepts_html};
+use crate::secret::Secret;
+use crate::session::{FlashMessage, get_session_user, session_id, set_flash};
+
+/// Id of the authenticated admin, stored in the request extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserId(pub Uuid);
+
+pub struct Credentials {
+    pub username: String,
+    /// Only exposed to hash or verify it, on a blocking thread
+    pub password: Secret<String>,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidCredentials,
+    Unexpected(anyhow::Error),
+}
+
+impl From<anyhow::Error> for AuthError {
+    fn from(e: anyhow::Error) -> Self {
+        AuthError::Unexpected(e)
+    }
+}
+
+/// Hash checked when the username doesn't exist, so both paths cost the same.
+/// Computing it is as slow as any hash, so it is only forced on blocking
+/// threads.
+static DUMMY_PASSWORD_HASH: LazyLock<String> = LazyLock::new(|| {
+    compute_password_hash("not-a-real-password-for-timing-parity")
+        .expect("Failed to hash dummy password")
+});
+
+fn argon2() -> Argon2<'static> {
+    // OWASP recommended minimum for Argon2id
+    Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(19456, 2, 1, None).expect("Valid Argon2 parameters"),
+    )
+}
+
+/// Argon2id PHC string for `password`, with a fresh random salt
+pub fn compute_password_hash(password: &str) -> Result<String, anyhow::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = argon2()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("Failed to hash password")?;
+    Ok(hash.to_string())
+}
+
+fn verify_password_hash(expected_password_hash: &str, password: &str) -> Result<(), AuthError> {
+    let expected = PasswordHash::new(expected_password_hash)
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("Failed to parse hash in PHC string format")?;
+    argon2()
+        .verify_password(password.as_bytes(), &expected)
+        .map_err(|_| AuthError::InvalidCredentials)
+}
+
+/// Check a username/password pair and return the matching user id
+pub async fn validate_credentials(
+    pool: &PgPool,
+    credentials: Credentials,
+) -> Result<Uuid, AuthError> {
+    let stored = sqlx::query!(
+        r#"SELECT user_id, password_hash FROM users WHERE username = $1"#,
+        credentials.username
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to look up stored credentials")?;
+
+    let (user_id, expected_password_hash) = match stored {
+        Some(row) => (Some(row.user_id), Some(row.password_hash)),
+        None => (None, None),
+    };
+
+    tokio::task::spawn_blocking(move || {
+        let expected_password_hash = expected_password_hash
+            .as_deref()
+            .unwrap_or(DUMMY_PASSWORD_HASH.as_str());
+        verify_password_hash(expected_password_hash, credentials.password.expose_secret())
+    })
+    .await
+    .context("Failed to spawn blocking task")??;
+
+    user_id.ok_or(AuthError::InvalidCredentials)
+}
+
+pub const MIN_PASSWORD_LENGTH: usize = 12;
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordPolicyViolation {
+    TooShort,
+    TooLong,
+    ContainsUsername,
+}
+
+/// Strength policy for new passwords: 12 to 128 characters, and not
+/// built around the username
+pub fn check_password_policy(
+    username: &str,
+    password: &str,
+) -> Result<(), PasswordPolicyViolation> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(PasswordPolicyViolation::TooShort);
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Err(PasswordPolicyViolation::TooLong);
+    }
+    if !username.is_empty()
+        && Zeroizing::new(password.to_lowercase()).contains(&username.to_lowercase())
+    {
+        return Err(PasswordPolicyViolation::ContainsUsername);
+    }
+    Ok(())
+}
+
+/// Replace a user's password hash. Hashing runs off the async runtime.
+pub async fn change_password(
+    executor: impl PgExecutor<'_>,
+    user_id: Uuid,
+    password: Secret<String>,
+) -> Result<(), anyhow::Error> {
+    let password_hash =
+        tokio::task::spawn_blocking(move || compute_password_hash(password.expose_secret()))
+            .await
+            .context("Failed to spawn blocking task")??;
+
+    sqlx::query!(
+        r#"UPDATE users SET password_hash = $1 WHERE user_id = $2"#,
+        password_hash,
+        user_id
+    )
+    .execute(executor)
+    .await
+    .context("Failed to change user's password")?;
+    Ok(())
+}
+
+pub async fn get_username(pool: &PgPool, user_id: Uuid) -> Result<String, anyhow::Error> {
+    let row = sqlx::query!(r#"SELECT username FROM users WHERE user_id = $1"#, user_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to look up username")?;
+    Ok(row.username)
+}
+
+/// Create a user with the given password. Hashing runs off the async runtime.
+pub async fn create_user(pool: &PgPool, credentials: Credentials) -> Result<Uuid, anyhow::Error> {
+    let password = credentials.password;
+    let password_hash =
+        tokio::task::spawn_blocking(move || compute_password_hash(password.expose_secret()))
+            .await
+            .context("Failed to spawn blocking task")??;
+
+    let user_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)"#,
+        user_id,
+        credentials.username,
+        password_hash
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store new user")?;
+    Ok(user_id)
+}
+
+/// Make sure the bootstrap admin from the settings exists, and keep its
+/// password reset address in sync
+pub async fn ensure_admin_user(
+    pool: &PgPool,
+    credentials: Credentials,
+    email: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let existing = sqlx::query!(
+        r#"SELECT user_id FROM users WHERE username = $1"#,
+        credentials.username
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|row| row.user_id);
+
+    let user_id = match existing {
+        Some(user_id) => user_id,
+        None => create_user(pool, credentials).await?,
+    };
+
+    if let Some(email) = email {
+        sqlx::query!(
+            r#"UPDATE users SET email = $1 WHERE user_id = $2"#,
+            email,
+            user_id
+        )
+        .execute(pool)
+        .await
+        .context("Failed to store admin email")?;
+    }
+    Ok(())
+}
+
+/// Parse an `Authorization: Basic ...` header
+pub fn basic_authentication(headers: &HeaderMap) -> Option<Credentials> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    // Wiped on drop, as only the password copy below is kept
+    let decoded = Zeroizing::new(String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?);
+    let (username, password) = decoded.split_once(':')?;
+
+    Some(Credentials {
+        username: username.to_string(),
+        password: Secret::new(password.to_string()),
+    })
+}
+
+/// Middleware guarding admin routes.
+/// API clients authenticate with HTTP basic auth on every request; browsers
+/// use the session cookie from `/login` and are sent there when it's missing.
+pub async fn require_login(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let user_id = if let Some(credentials) = basic_authentication(request.headers()) {
+        match validate_credentials(&state.db, credentials).await {
+            Ok(user_id) => user_id,
+            Err(AuthError::InvalidCredentials) => return unauthorized(),
+            Err(AuthError::Unexpected(e)) => {
+                tracing::error!(error = ?e, "Failed to validate credentials");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    } else {
+        let session_user = match session_id(&jar) {
+            Some(session_id) => get_session_user(&state.db, &session_id).await,
+            None => Ok(None),
+        };
+        match session_user {
+            Ok(Some(user_id)) => user_id,
+            Ok(None) if accepts_html(request.headers()) => {
+                let jar = set_flash(jar, FlashMessage::LoginRequired, state.secure_cookies());
+                return (jar, Redirect::to("/login")).into_response();
+            }
+            Ok(None) => return unauthorized(),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to load session");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    };
+
+    request.extensions_mut().insert(UserId(user_id));
+    next.run(request).await
+}
+
+fn unauthorized() -> Response {
+    let mut response = StatusCode::UNAUTHORIZED.into_response();
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static(r#"Basic realm="admin""#),
+    );
+    response
+}
@@ -1,75 +1,613 @@
 //! src/configuration.rs
+//! Settings are layered, later sources overriding earlier ones:
+//! 1. `configuration/base.{yaml,toml}`
+//! 2. `configuration/<APP_ENVIRONMENT>.{yaml,toml}`, `local` by default
+//! 3. `APP__*` environment variables, e.g. `APP__DATABASE__PORT` for `database.port`
+//!
+//! The `database` and `email` sections are deserialized whole, then
+//! validated. serde stops at the first bad value, so when a section fails
+//! its keys are read one by one instead, and every problem is reported at
+//! once.
 use crate::routes::subscriptions::SubscriberEmail;
-use serde::{Deserialize, Serialize};
-use std::env;
+use crate::secret::Secret;
+use crate::strict_form::StrictFormLimits;
+use config::{Config, File, Map};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use sqlx::PgPool;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions, PgSslMode};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-/// Application settings loaded from environment variables
+/// Application settings
 #[derive(Debug, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application_port: u16,
+    pub base_url: String,
+    pub hmac_secret: Secret<String>,
     pub email_settings: EmailSettings,
+    pub admin: Option<AdminSettings>,
+    /// Default `StrictForm` limits for routes that don't set their own
+    pub form_limits: StrictFormLimits,
+    /// How long a shutdown waits for in-flight requests and the delivery
+    /// worker before dropping them
+    pub shutdown_timeout_secs: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Optional admin account created on startup if it doesn't exist yet
+#[derive(Debug, Clone)]
+pub struct AdminSettings {
+    pub username: String,
+    pub password: Secret<String>,
+    /// Where password reset links are sent
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct EmailSettings {
-    pub sender_email: SubscriberEmail,
+    pub sender: SubscriberEmail,
     pub service_url: String,
-    pub api_token: String,
+    pub api_token: Secret<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct DatabaseSettings {
     pub username: String,
-    pub password: String,
+    pub password: Secret<String>,
     pub host: String,
     pub port: u16,
     pub database_name: String,
+    #[serde(default)]
+    pub ssl_mode: DatabaseSslMode,
+    /// CA bundle for verifying the server; the system roots are used if unset
+    pub ca_certificate: Option<PathBuf>,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: u32,
+    #[serde(default)]
+    pub min_connections: u32,
+    /// How long a request may wait for a free connection
+    #[serde(default = "default_acquire_timeout_secs")]
+    pub acquire_timeout_secs: u64,
+    /// Idle connections above `min_connections` are closed after this
+    /// long; 0 keeps them open
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// Server-side limit for every statement; `None` leaves the server default
+    pub statement_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub connect_retry: ConnectRetrySettings,
+    /// Serve requests before the database is reachable, reporting not ready
+    /// on the health check, instead of failing after `max_wait_secs`
+    #[serde(default)]
+    pub start_degraded: bool,
+}
+
+fn default_max_connections() -> u32 {
+    10
+}
+
+fn default_acquire_timeout_secs() -> u64 {
+    5
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    600
+}
+
+/// How startup waits for the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ConnectRetrySettings {
+    /// Delay after the first failed attempt; it doubles after each one
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Give up once this much time has passed since the first attempt
+    pub max_wait_secs: u64,
+}
+
+impl Default for ConnectRetrySettings {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: 250,
+            max_backoff_ms: 5_000,
+            max_wait_secs: 60,
+        }
+    }
+}
+
+impl ConnectRetrySettings {
+    pub fn initial_backoff(&self) -> Duration {
+        Duration::from_millis(self.initial_backoff_ms)
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_millis(self.max_backoff_ms)
+    }
+
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_secs(self.max_wait_secs)
+    }
+}
+
+/// TLS for the database connection, with libpq's `sslmode` semantics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DatabaseSslMode {
+    Disable,
+    #[default]
+    Prefer,
+    Require,
+    VerifyFull,
+}
+
+impl From<DatabaseSslMode> for PgSslMode {
+    fn from(mode: DatabaseSslMode) -> Self {
+        match mode {
+            DatabaseSslMode::Disable => PgSslMode::Disable,
+            DatabaseSslMode::Prefer => PgSslMode::Prefer,
+            DatabaseSslMode::Require => PgSslMode::Require,
+            DatabaseSslMode::VerifyFull => PgSslMode::VerifyFull,
+        }
+    }
+}
+
+/// The deployment environment, picked with `APP_ENVIRONMENT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    Local,
+    Production,
+}
+
+impl Environment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Environment::Local => "local",
+            Environment::Production => "production",
+        }
+    }
+}
+
+impl TryFrom<String> for Environment {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "local" => Ok(Environment::Local),
+            "production" => Ok(Environment::Production),
+            other => Err(format!(
+                "{other} is not a supported environment, use `local` or `production`"
+            )),
+        }
+    }
+}
+
+/// Everything wrong with the configuration, reported together so a
+/// deployment can be fixed in one go
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<ConfigProblem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigProblem {
+    /// A required key is not set in any source
+    Missing { key: String },
+    /// A key is set but its value is unusable
+    Invalid { key: String, reason: String },
+    /// A configuration file could not be read or parsed
+    Source(String),
+}
+
+impl ConfigProblem {
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            ConfigProblem::Missing { key } | ConfigProblem::Invalid { key, .. } => Some(key),
+            ConfigProblem::Source(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigProblem::Missing { key } => {
+                write!(
+                    f,
+                    "{key} is missing (set it in a file or as {})",
+                    env_name(key)
+                )
+            }
+            ConfigProblem::Invalid { key, reason } => write!(f, "{key} is invalid: {reason}"),
+            ConfigProblem::Source(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// `database.port` -> `APP__DATABASE__PORT`
+fn env_name(key: &str) -> String {
+    format!("APP__{}", key.replace('.', "__").to_uppercase())
 }
 
 impl Settings {
-    /// Load settings from environment variables
-    /// Panics if any required variable is missing or invalid
-    pub fn from_env() -> Self {
-        let database = DatabaseSettings {
-            username: env::var("APP__DATABASE__USERNAME").expect("APP__DATABASE__USERNAME not set"),
-            password: env::var("APP__DATABASE__PASSWORD").expect("APP__DATABASE__PASSWORD not set"),
-            host: env::var("APP__DATABASE__HOST").expect("APP__DATABASE__HOST not set"),
-            port: env::var("APP__DATABASE__PORT")
-                .expect("Missing APP__DATABASE__PORT")
-                .parse()
-                .expect("APP__DATABASE__PORT must be a number"),
-            database_name: env::var("APP__DATABASE__DATABASE_NAME")
-                .expect("APP__DATABASE__DATABASE_NAME not set"),
+    /// Load settings from `configuration/` and the process environment
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::load_from(Path::new("configuration"), None)
+    }
+
+    /// Load settings from the files in `directory`. `env` stands in for the
+    /// process environment when given.
+    pub fn load_from(
+        directory: &Path,
+        env: Option<Map<String, String>>,
+    ) -> Result<Self, ConfigError> {
+        let environment = env
+            .as_ref()
+            .map_or_else(
+                || std::env::var("APP_ENVIRONMENT").ok(),
+                |env| env.get("APP_ENVIRONMENT").cloned(),
+            )
+            .unwrap_or_else(|| "local".to_string());
+        let environment = Environment::try_from(environment).map_err(|reason| ConfigError {
+            problems: vec![ConfigProblem::Invalid {
+                key: "APP_ENVIRONMENT".to_string(),
+                reason,
+            }],
+        })?;
+
+        let config = Config::builder()
+            .add_source(File::with_name(&directory.join("base").to_string_lossy()))
+            .add_source(File::with_name(
+                &directory.join(environment.as_str()).to_string_lossy(),
+            ))
+            .add_source(
+                config::Environment::with_prefix("APP")
+                    .prefix_separator("__")
+                    .separator("__")
+                    .source(env),
+            )
+            .build()
+            .map_err(|e| ConfigError {
+                problems: vec![ConfigProblem::Source(e.to_string())],
+            })?;
+
+        Self::from_config(&config)
+    }
+
+    /// Read and validate every key, collecting all problems before failing
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut reader = Reader {
+            config,
+            problems: Vec::new(),
         };
 
-        let application_port = env::var("APP__APPLICATION_PORT")
-            .unwrap_or_else(|_| "8000".to_string())
-            .parse::<u16>()
-            .expect("APP__APPLICATION_PORT must be a valid u16");
+        let database = reader.section("database", DatabaseSettings::read_each_key);
 
-        let email_settings = EmailSettings {
-            sender_email: SubscriberEmail {
-                email: env::var("APP__EMAIL__SENDER").expect("APP__EMAIL__SENDER not set"),
-            },
-            service_url: env::var("APP__EMAIL__SERVICE_URL")
-                .expect("APP__EMAIL__SERVICE_URL not set"),
-            api_token: env::var("APP__EMAIL__API_TOKEN").expect("APP__EMAIL__API_TOKEN not set"),
+        let application_port = reader.optional("application_port").unwrap_or(8000);
+
+        // Public address of the app, used to build links in outgoing emails
+        let base_url = reader
+            .optional("application_base_url")
+            .unwrap_or_else(|| format!("http://localhost:{application_port}"));
+
+        let shutdown_timeout_secs = reader
+            .optional("application_shutdown_timeout_secs")
+            .unwrap_or(30);
+
+        // Key for signing unsubscribe links
+        let hmac_secret = reader.required("application_hmac_secret");
+
+        let email_settings = reader.section("email", EmailSettings::read_each_key);
+
+        let admin = match (
+            reader.optional("admin.username"),
+            reader.optional("admin.password"),
+        ) {
+            (Some(username), Some(password)) => Some(AdminSettings {
+                username,
+                password,
+                email: reader.optional("admin.email"),
+            }),
+            _ => None,
+        };
+
+        let defaults = StrictFormLimits::default();
+        let form_limits = StrictFormLimits {
+            max_body_bytes: reader
+                .optional("form.max_body_bytes")
+                .unwrap_or(defaults.max_body_bytes),
+            max_fields: reader
+                .optional("form.max_fields")
+                .unwrap_or(defaults.max_fields),
+            max_key_len: reader
+                .optional("form.max_key_length")
+                .unwrap_or(defaults.max_key_len),
+            max_value_len: reader
+                .optional("form.max_value_length")
+                .unwrap_or(defaults.max_value_len),
         };
 
-        Settings {
+        // A key that is already missing or unreadable needs no second report
+        let checks = database
+            .validate()
+            .into_iter()
+            .chain(email_settings.validate());
+        for problem in checks.collect::<Vec<_>>() {
+            if !reader.problems.iter().any(|p| p.key() == problem.key()) {
+                reader.problems.push(problem);
+            }
+        }
+        if !reader.problems.is_empty() {
+            return Err(ConfigError {
+                problems: reader.problems,
+            });
+        }
+
+        Ok(Settings {
             database,
             application_port,
+            base_url,
+            hmac_secret,
             email_settings,
+            admin,
+            form_limits,
+            shutdown_timeout_secs,
+        })
+    }
+}
+
+/// Reads one key at a time so a bad value doesn't hide the ones after it
+struct Reader<'a> {
+    config: &'a Config,
+    problems: Vec<ConfigProblem>,
+}
+
+impl Reader<'_> {
+    /// Deserialize the `section` table whole. If that fails, `read_each_key`
+    /// builds it one key at a time instead, recording each bad key.
+    fn section<T: DeserializeOwned>(
+        &mut self,
+        section: &str,
+        read_each_key: fn(&mut Self) -> T,
+    ) -> T {
+        match self.config.get::<T>(section) {
+            Ok(value) => value,
+            Err(e) => {
+                let before = self.problems.len();
+                let value = read_each_key(self);
+                // Not expected, but the error must not be lost
+                if self.problems.len() == before {
+                    self.problems.push(ConfigProblem::Invalid {
+                        key: section.to_string(),
+                        reason: e.to_string(),
+                    });
+                }
+                value
+            }
+        }
+    }
+
+    fn get<T: DeserializeOwned>(&mut self, key: &str) -> Option<Option<T>> {
+        match self.config.get::<T>(key) {
+            Ok(value) => Some(Some(value)),
+            Err(config::ConfigError::NotFound(_)) => Some(None),
+            Err(e) => {
+                self.problems.push(ConfigProblem::Invalid {
+                    key: key.to_string(),
+                    reason: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    /// `None` if the key is unset or invalid; an invalid value is recorded
+    fn optional<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        self.get(key).flatten()
+    }
+
+    /// `None` if the key is unset or invalid; either problem is recorded
+    fn require<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        let value = self.get(key)?;
+        if value.is_none() {
+            self.problems.push(ConfigProblem::Missing {
+                key: key.to_string(),
+            });
         }
+        value
+    }
+
+    /// Like `require`, with a placeholder default standing in for a missing
+    /// or invalid value. The problem is recorded, so it never reaches a
+    /// `Settings`.
+    fn required<T: DeserializeOwned + Default>(&mut self, key: &str) -> T {
+        self.require(key).unwrap_or_default()
     }
 }
 
 impl DatabaseSettings {
-    pub fn connection_string(&self) -> String {
-        format!(
-            "postgres://{}:{}@{}:{}/{}",
-            self.username, self.password, self.host, self.port, self.database_name
-        )
+    /// Placeholders stand in for bad values; each one is recorded as a problem
+    fn read_each_key(reader: &mut Reader<'_>) -> Self {
+        let retry = ConnectRetrySettings::default();
+        DatabaseSettings {
+            username: reader.required("database.username"),
+            password: reader.required("database.password"),
+            host: reader.required("database.host"),
+            port: reader.required("database.port"),
+            database_name: reader.required("database.database_name"),
+            ssl_mode: reader.optional("database.ssl_mode").unwrap_or_default(),
+            ca_certificate: reader.optional("database.ca_certificate"),
+            max_connections: reader
+                .optional("database.max_connections")
+                .unwrap_or_else(default_max_connections),
+            min_connections: reader
+                .optional("database.min_connections")
+                .unwrap_or_default(),
+            acquire_timeout_secs: reader
+                .optional("database.acquire_timeout_secs")
+                .unwrap_or_else(default_acquire_timeout_secs),
+            idle_timeout_secs: reader
+                .optional("database.idle_timeout_secs")
+                .unwrap_or_else(default_idle_timeout_secs),
+            statement_timeout_ms: reader.optional("database.statement_timeout_ms"),
+            connect_retry: ConnectRetrySettings {
+                initial_backoff_ms: reader
+                    .optional("database.connect_retry.initial_backoff_ms")
+                    .unwrap_or(retry.initial_backoff_ms),
+                max_backoff_ms: reader
+                    .optional("database.connect_retry.max_backoff_ms")
+                    .unwrap_or(retry.max_backoff_ms),
+                max_wait_secs: reader
+                    .optional("database.connect_retry.max_wait_secs")
+                    .unwrap_or(retry.max_wait_secs),
+            },
+            start_degraded: reader
+                .optional("database.start_degraded")
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Built field by field, so no character in the password needs escaping
+    pub fn connect_options(&self) -> PgConnectOptions {
+        let mut options = PgConnectOptions::new_without_pgpass()
+            .host(&self.host)
+            .port(self.port)
+            .username(&self.username)
+            .password(self.password.expose_secret())
+            .database(&self.database_name)
+            .ssl_mode(self.ssl_mode.into());
+        if let Some(ca_certificate) = &self.ca_certificate {
+            options = options.ssl_root_cert(ca_certificate);
+        }
+        if let Some(timeout) = self.statement_timeout_ms {
+            options = options.options([("statement_timeout", timeout.to_string())]);
+        }
+        options
+    }
+
+    pub fn pool_options(&self) -> PgPoolOptions {
+        PgPoolOptions::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(Duration::from_secs(self.acquire_timeout_secs))
+            .idle_timeout(
+                Some(self.idle_timeout_secs)
+                    .filter(|&secs| secs > 0)
+                    .map(Duration::from_secs),
+            )
+    }
+
+    /// A pool that only connects once a connection is first needed
+    pub fn connect_lazy(&self) -> PgPool {
+        self.pool_options()
+            .connect_lazy_with(self.connect_options())
+    }
+
+    /// Checks beyond what deserialization already guarantees
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        if self.port == 0 {
+            problems.push(ConfigProblem::Invalid {
+                key: "database.port".to_string(),
+                reason: "the port must not be 0".to_string(),
+            });
+        }
+        for (key, value) in [
+            ("database.username", &self.username),
+            ("database.host", &self.host),
+            ("database.database_name", &self.database_name),
+        ] {
+            if value.trim().is_empty() {
+                problems.push(ConfigProblem::Invalid {
+                    key: key.to_string(),
+                    reason: "the value must not be empty".to_string(),
+                });
+            }
+        }
+        if self.max_connections == 0 {
+            problems.push(ConfigProblem::Invalid {
+                key: "database.max_connections".to_string(),
+                reason: "the pool needs at least one connection".to_string(),
+            });
+        } else if self.min_connections > self.max_connections {
+            problems.push(ConfigProblem::Invalid {
+                key: "database.min_connections".to_string(),
+                reason: format!(
+                    "{} is more than database.max_connections ({})",
+                    self.min_connections, self.max_connections
+                ),
+            });
+        }
+        let retry = &self.connect_retry;
+        if retry.initial_backoff_ms == 0 || retry.max_backoff_ms < retry.initial_backoff_ms {
+            problems.push(ConfigProblem::Invalid {
+                key: "database.connect_retry.max_backoff_ms".to_string(),
+                reason: format!(
+                    "the backoff must grow from a positive initial_backoff_ms, got {}..{}",
+                    retry.initial_backoff_ms, retry.max_backoff_ms
+                ),
+            });
+        }
+        if let Some(ca_certificate) = &self.ca_certificate {
+            if self.ssl_mode == DatabaseSslMode::Disable {
+                problems.push(ConfigProblem::Invalid {
+                    key: "database.ca_certificate".to_string(),
+                    reason: "a CA bundle is set but ssl_mode is disable".to_string(),
+                });
+            } else if !ca_certificate.is_file() {
+                problems.push(ConfigProblem::Invalid {
+                    key: "database.ca_certificate".to_string(),
+                    reason: format!("{} is not a readable file", ca_certificate.display()),
+                });
+            }
+        }
+        problems
+    }
+}
+
+impl EmailSettings {
+    /// Placeholders stand in for bad values; each one is recorded as a problem
+    fn read_each_key(reader: &mut Reader<'_>) -> Self {
+        EmailSettings {
+            // Same placeholder convention as `Reader::required`
+            sender: reader
+                .require("email.sender")
+                .unwrap_or_else(|| SubscriberEmail {
+                    email: String::new(),
+                }),
+            service_url: reader.required("email.service_url"),
+            api_token: reader.required("email.api_token"),
+        }
+    }
+
+    /// Checks beyond what deserialization already guarantees
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        match reqwest::Url::parse(&self.service_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => problems.push(ConfigProblem::Invalid {
+                key: "email.service_url".to_string(),
+                reason: format!("{} is not an http(s) URL", self.service_url),
+            }),
+        }
+        if self.api_token.expose_secret().trim().is_empty() {
+            problems.push(ConfigProblem::Invalid {
+                key: "email.api_token".to_string(),
+                reason: "the value must not be empty".to_string(),
+            });
+        }
+        problems
     }
 }
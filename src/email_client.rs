@@ -1,13 +1,47 @@
 //! src/email_client.rs
 use crate::routes::subscriptions::SubscriberEmail;
+use crate::secret::Secret;
+use chrono::{DateTime, Utc};
 use reqwest::Client;
 use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub struct EmailClient {
     pub sender: SubscriberEmail,
     pub url: String,
-    pub token: String,
+    pub token: Secret<String>,
+    pub status: ProviderStatus,
+}
+
+/// Outcome of the latest call to the email provider, shared by all clones
+/// of an `EmailClient`. Readiness reports it instead of probing the provider.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderStatus(Arc<Mutex<Option<ProviderCall>>>);
+
+#[derive(Debug, Clone)]
+pub struct ProviderCall {
+    /// False when the provider could not be reached or answered with a 5xx;
+    /// a 4xx is about the message, not the provider
+    pub reachable: bool,
+    pub at: DateTime<Utc>,
+    pub latency: Duration,
+    pub error: Option<String>,
+}
+
+impl ProviderStatus {
+    /// `None` until the first email has been sent
+    pub fn last_call(&self) -> Option<ProviderCall> {
+        self.0
+            .lock()
+            .expect("status lock is never poisoned")
+            .clone()
+    }
+
+    fn record(&self, call: ProviderCall) {
+        *self.0.lock().expect("status lock is never poisoned") = Some(call);
+    }
 }
 
 impl EmailClient {
@@ -17,35 +51,79 @@ impl EmailClient {
         subject: String,
         html_content: String,
         text_content: String,
+        headers: &[EmailHeader],
     ) -> Result<(), String> {
         let client = Client::new();
 
         let payload = SendEmailRequest {
-            from: self.sender.clone(),
-            to: recipient.clone(),
-            subject: subject.clone(),
-            html_body: html_content.clone(),
-            text_body: text_content.clone(),
+            from: self.sender.as_str(),
+            to: recipient.as_str(),
+            subject: &subject,
+            html_body: &html_content,
+            text_body: &text_content,
+            headers,
         };
-        let res = client
+        let started = Instant::now();
+        let result = client
             .post(self.url.clone()) // TODO: load from config
             .header("Accept", "application/json")
             .header("Content-Type", "application/json")
-            .header("X-Postmark-Server-Token", self.token.clone()) // TODO: load
+            .header("X-Postmark-Server-Token", self.token.expose_secret()) // TODO: load
             // from config
             .json(&payload)
             .send()
             .await;
 
-        Ok(())
+        let reachable = result
+            .as_ref()
+            .is_ok_and(|response| !response.status().is_server_error());
+        let result = result
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+        self.status.record(ProviderCall {
+            reachable,
+            at: Utc::now(),
+            latency: started.elapsed(),
+            error: result.as_ref().err().cloned(),
+        });
+
+        result
+    }
+}
+
+/// Extra header to set on the outgoing message
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl EmailHeader {
+    /// RFC 8058 one-click unsubscribe headers for list mail
+    pub fn list_unsubscribe(unsubscribe_url: &str) -> Vec<EmailHeader> {
+        vec![
+            EmailHeader {
+                name: "List-Unsubscribe".to_string(),
+                value: format!("<{unsubscribe_url}>"),
+            },
+            EmailHeader {
+                name: "List-Unsubscribe-Post".to_string(),
+                value: "List-Unsubscribe=One-Click".to_string(),
+            },
+        ]
     }
 }
 
 #[derive(Serialize)]
-struct SendEmailRequest {
-    from: SubscriberEmail,
-    to: SubscriberEmail,
-    subject: String,
-    html_body: String,
-    text_body: String,
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    headers: &'a [EmailHeader],
 }
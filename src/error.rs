@@ -0,0 +1,212 @@
+//! src/error.rs
+//! RFC 7807 problem details for API errors.
+//!
+//! Handlers and extractors return `ApiError`, which renders as
+//! `application/problem+json`. The `problem_details` middleware adds the
+//! request id to every such body on its way out, so a client can quote it
+//! when reporting a failure.
+use axum::{
+    body::{Body, to_bytes},
+    extract::Request,
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use serde_json::Value;
+use tower_http::request_id::RequestId;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Problem bodies are small; this only bounds the buffering in `problem_details`
+const MAX_PROBLEM_BYTES: usize = 64 * 1024;
+
+/// An error response in problem+json form
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiError {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: String,
+    #[serde(serialize_with = "serialize_status")]
+    pub status: StatusCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+/// A problem with one input field. `code` is a stable machine-readable
+/// reason such as `missing` or `too_long`; `message` is for humans.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+/// Error of the `TryFrom` conversions behind validated input types.
+///
+/// serde only passes errors along as strings, so the `Display` form is
+/// `code: message`, which `FieldError::from_message` takes apart again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ApiError {
+    /// `problem` is a short slug identifying the kind of error; it becomes
+    /// the `type` URI `/problems/<problem>`
+    pub fn new(status: StatusCode, problem: &str, title: impl Into<String>) -> Self {
+        Self {
+            kind: format!("/problems/{problem}"),
+            title: title.into(),
+            status,
+            detail: None,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with_field_errors(mut self, errors: Vec<FieldError>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    pub fn bad_request(problem: &str, title: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, problem, title)
+    }
+
+    /// Details of internal failures stay in the logs
+    pub fn internal() -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "Internal server error",
+        )
+    }
+}
+
+impl FieldError {
+    pub fn new(
+        field: impl Into<String>,
+        code: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            field: field.into(),
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Build from a deserialization error message, recovering the code of
+    /// a `ValidationError` if there is one
+    pub fn from_message(field: impl Into<String>, message: &str) -> Self {
+        match message.split_once(": ") {
+            Some((code, rest))
+                if !code.is_empty()
+                    && code.bytes().all(|b| b.is_ascii_lowercase() || b == b'_') =>
+            {
+                Self::new(field, code, rest)
+            }
+            _ => Self::new(field, "invalid", message),
+        }
+    }
+}
+
+impl ValidationError {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.detail {
+            Some(detail) => write!(f, "{}: {detail}", self.title),
+            None => f.write_str(&self.title),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::to_vec(&self).expect("ApiError always serializes");
+        (
+            self.status,
+            [(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON))],
+            body,
+        )
+            .into_response()
+    }
+}
+
+fn serialize_status<S: serde::Serializer>(status: &StatusCode, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u16(status.as_u16())
+}
+
+/// Middleware adding `request_id` to problem+json responses. Must run inside
+/// `SetRequestIdLayer` so the id is already on the request.
+pub async fn problem_details(request: Request, next: Next) -> Response {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(str::to_string);
+
+    let response = next.run(request).await;
+
+    let is_problem = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes() == PROBLEM_JSON.as_bytes());
+    let Some(request_id) = request_id.filter(|_| is_problem) else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_PROBLEM_BYTES).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let body = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut problem)) => {
+            problem.insert("request_id".to_string(), Value::String(request_id));
+            serde_json::to_vec(&problem).expect("JSON objects always serialize")
+        }
+        _ => bytes.to_vec(),
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Recover field names from serde's "missing field `x`" style messages
+pub(crate) fn field_errors_from_serde(message: &str) -> Vec<FieldError> {
+    for (prefix, code) in [
+        ("missing field `", "missing"),
+        ("unknown field `", "unknown"),
+        ("duplicate field `", "duplicate"),
+    ] {
+        if let Some(rest) = message.strip_prefix(prefix)
+            && let Some((field, _)) = rest.split_once('`')
+        {
+            return vec![FieldError::new(field, code, message)];
+        }
+    }
+    Vec::new()
+}
@@ -0,0 +1,466 @@
+//! src/form_deserializer.rs
+//! serde `Deserializer` for decoded form pairs that reports every bad field.
+//!
+//! A derived `Deserialize` impl gives up at the first error, so we run it
+//! in passes. Each pass that fails on a field records a `FieldError` and
+//! leaves that field out of the next pass, until a pass gets through all
+//! remaining fields. Absent struct fields are fed a probe value that only
+//! `Option` accepts, which is how required fields are found to be missing.
+//! A well-formed form is deserialized in a single pass. The pairs are
+//! grouped by key once up front, so a pass costs time linear in the number
+//! of keys and fields however many passes a flood of bad fields takes.
+//!
+//! A key may only appear more than once if its field is a sequence, such as
+//! `Vec<T>`; every other repeated key is reported as `duplicate`.
+//!
+//! Keys and values are handed to the visitor as borrowed strings, so types
+//! deserializing into `&str` or `Cow<str>` don't copy them.
+use serde::de::{
+    self, Deserialize, DeserializeSeed, MapAccess, SeqAccess, Visitor,
+    value::BorrowedStrDeserializer,
+};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::error::{FieldError, field_errors_from_serde};
+
+/// A decoded key/value pair, borrowed from the body unless decoding changed it
+pub type FormPair<'a> = (Cow<'a, str>, Cow<'a, str>);
+
+/// Deserialize `T` from decoded pairs, collecting an error for every field
+/// that is missing, unknown or fails its own validation
+pub fn from_pairs<'de, T: Deserialize<'de>>(
+    pairs: &'de [FormPair<'de>],
+) -> Result<T, Vec<FieldError>> {
+    let grouped = GroupedPairs::new(pairs);
+    let mut skipped: HashSet<&'de str> = HashSet::new();
+    let mut errors: Vec<FieldError> = Vec::new();
+
+    loop {
+        let mut failure = None;
+        let result = T::deserialize(FormDeserializer {
+            grouped: &grouped,
+            skipped: &skipped,
+            failure: &mut failure,
+        });
+
+        match (result, failure) {
+            (_, Some(Failure { field, error })) => {
+                errors.push(error);
+                skipped.insert(field);
+            }
+            // Fields skipped for being absent may turn out to have a default
+            (Ok(t), None) if errors.iter().all(|e| e.code == MISSING) => return Ok(t),
+            (Ok(_), None) => return Err(errors),
+            (Err(e), None) => {
+                if errors.is_empty() {
+                    errors = field_errors_from_serde(&e.0);
+                }
+                if errors.is_empty() {
+                    errors.push(FieldError::from_message("", &e.0));
+                }
+                return Err(errors);
+            }
+        }
+    }
+}
+
+const MISSING: &str = "missing";
+
+/// The values of each key, keys in order of first appearance
+struct GroupedPairs<'de> {
+    keys: Vec<(&'de str, Vec<&'de str>)>,
+    positions: HashMap<&'de str, usize>,
+}
+
+impl<'de> GroupedPairs<'de> {
+    fn new(pairs: &'de [FormPair<'de>]) -> Self {
+        let mut keys: Vec<(&'de str, Vec<&'de str>)> = Vec::new();
+        let mut positions: HashMap<&'de str, usize> = HashMap::new();
+        for (key, value) in pairs {
+            let key: &'de str = key;
+            let position = *positions.entry(key).or_insert_with(|| {
+                keys.push((key, Vec::new()));
+                keys.len() - 1
+            });
+            keys[position].1.push(value);
+        }
+        Self { keys, positions }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.positions.contains_key(key)
+    }
+}
+
+/// The field that ended a pass
+struct Failure<'de> {
+    field: &'de str,
+    error: FieldError,
+}
+
+#[derive(Debug)]
+pub struct FormError(String);
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for FormError {}
+
+impl de::Error for FormError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        FormError(msg.to_string())
+    }
+}
+
+struct FormDeserializer<'de, 'f> {
+    grouped: &'f GroupedPairs<'de>,
+    skipped: &'f HashSet<&'de str>,
+    failure: &'f mut Option<Failure<'de>>,
+}
+
+impl<'de> de::Deserializer<'de> for FormDeserializer<'de, '_> {
+    type Error = FormError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_struct("", &[], visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(FormMapAccess {
+            grouped: self.grouped,
+            fields,
+            skipped: self.skipped,
+            position: 0,
+            current: None,
+            failure: self.failure,
+        })
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct enum identifier ignored_any
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Entry<'de, 'f> {
+    Present(&'de str, &'f [&'de str]),
+    Absent(&'de str),
+}
+
+impl<'de> Entry<'de, '_> {
+    fn key(self) -> &'de str {
+        match self {
+            Entry::Present(k, _) | Entry::Absent(k) => k,
+        }
+    }
+}
+
+/// Walks the distinct keys of the form, then the struct fields that are not
+/// in it, leaving out whatever an earlier pass failed on
+struct FormMapAccess<'de, 'f> {
+    grouped: &'f GroupedPairs<'de>,
+    fields: &'static [&'static str],
+    skipped: &'f HashSet<&'de str>,
+    position: usize,
+    current: Option<Entry<'de, 'f>>,
+    failure: &'f mut Option<Failure<'de>>,
+}
+
+impl<'de, 'f> FormMapAccess<'de, 'f> {
+    fn next_entry(&mut self) -> Option<Entry<'de, 'f>> {
+        let keys = &self.grouped.keys;
+        while self.position < keys.len() + self.fields.len() {
+            let position = self.position;
+            self.position += 1;
+
+            if let Some((key, values)) = keys.get(position) {
+                if !self.skipped.contains(key) {
+                    return Some(Entry::Present(key, values));
+                }
+            } else {
+                let field = self.fields[position - keys.len()];
+                if !self.grouped.contains(field) && !self.skipped.contains(field) {
+                    return Some(Entry::Absent(field));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<'de> MapAccess<'de> for FormMapAccess<'de, '_> {
+    type Error = FormError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        let Some(entry) = self.next_entry() else {
+            return Ok(None);
+        };
+        let key = entry.key();
+        self.current = Some(entry);
+        seed.deserialize(BorrowedStrDeserializer::new(key))
+            .map(Some)
+            .inspect_err(|e: &FormError| {
+                *self.failure = Some(Failure {
+                    field: key,
+                    error: field_errors_from_serde(&e.0)
+                        .pop()
+                        .unwrap_or_else(|| FieldError::from_message(key, &e.0)),
+                });
+            })
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let entry = self
+            .current
+            .take()
+            .ok_or_else(|| FormError("value requested before key".to_string()))?;
+        let key = entry.key();
+        let result = match entry {
+            Entry::Present(_, values) => seed.deserialize(ValuesDeserializer(values)),
+            Entry::Absent(_) => seed.deserialize(AbsentDeserializer),
+        };
+        result.inspect_err(|e| {
+            *self.failure = Some(Failure {
+                field: key,
+                error: if e.0 == ABSENT {
+                    FieldError::new(key, MISSING, format!("missing field `{key}`"))
+                } else {
+                    FieldError::from_message(key, &e.0)
+                },
+            });
+        })
+    }
+}
+
+/// Every value sent for one key. Sequences take all of them; everything
+/// else needs exactly one.
+struct ValuesDeserializer<'de, 'f>(&'f [&'de str]);
+
+impl<'de> ValuesDeserializer<'de, '_> {
+    fn single(&self) -> Result<ValueDeserializer<'de>, FormError> {
+        match self.0 {
+            [value] => Ok(ValueDeserializer(value)),
+            _ => Err(FormError(
+                "duplicate: The field was given more than once".to_string(),
+            )),
+        }
+    }
+}
+
+macro_rules! forward_to_single {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                de::Deserializer::$method(self.single()?, visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ValuesDeserializer<'de, '_> {
+    type Error = FormError;
+
+    forward_to_single! {
+        deserialize_any deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32
+        deserialize_i64 deserialize_i128 deserialize_u8 deserialize_u16 deserialize_u32
+        deserialize_u64 deserialize_u128 deserialize_f32 deserialize_f64 deserialize_char
+        deserialize_str deserialize_string deserialize_bytes deserialize_byte_buf
+        deserialize_unit deserialize_map deserialize_identifier
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_seq(ValueSeqAccess(self.0.iter().copied()))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_unit_struct(self.single()?, name, visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_struct(self.single()?, name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_enum(self.single()?, name, variants, visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+}
+
+/// Elements of a repeated key, one value each
+struct ValueSeqAccess<I>(I);
+
+impl<'de, I: Iterator<Item = &'de str>> SeqAccess<'de> for ValueSeqAccess<I> {
+    type Error = FormError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        self.0
+            .next()
+            .map(|value| seed.deserialize(ValueDeserializer(value)))
+            .transpose()
+    }
+}
+
+/// A single decoded form value. Scalars are parsed from the text.
+struct ValueDeserializer<'de>(&'de str);
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self.0.parse() {
+                    Ok(v) => visitor.$visit(v),
+                    Err(e) => Err(de::Error::custom(e)),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'de> {
+    type Error = FormError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let deserializer: BorrowedStrDeserializer<'de, FormError> =
+            BorrowedStrDeserializer::new(self.0);
+        visitor.visit_enum(deserializer)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier
+    }
+}
+
+/// Error text that marks a required field as absent
+const ABSENT: &str = "field is absent";
+
+/// Stands in for a field that is not in the form. Only `Option` accepts it.
+struct AbsentDeserializer;
+
+impl<'de> de::Deserializer<'de> for AbsentDeserializer {
+    type Error = FormError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(FormError(ABSENT.to_string()))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_none()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
@@ -0,0 +1,486 @@
+//! src/idempotency.rs
+//! `Idempotency-Key` support for state-changing requests.
+//!
+//! The first request with a given key claims a row in the `idempotency`
+//! table, runs the handler and stores the response. Retries with the same
+//! key get the stored response replayed instead of running the handler
+//! again. A retry that arrives while the first request is still running
+//! is answered with 409 Conflict, unless the claim is older than
+//! `CLAIM_TIMEOUT`: then the first request is presumed lost (a crash, or a
+//! handler cancelled at shutdown) and the retry takes over. Keys expire
+//! after `KEY_TTL`.
+//!
+//! Keys are scoped to the authenticated user, so the middleware only acts
+//! behind `require_login`; anonymous requests pass through untouched.
+use axum::{
+    body::{Body, Bytes, HttpBody, to_bytes},
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use http_body_util::BodyExt;
+use hyper::body::Frame;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::authentication::UserId;
+use crate::error::ApiError;
+use crate::routes::{AppState, NEWSLETTER_MAX_BODY_BYTES};
+use crate::strict_form::StrictFormLimits;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Set on responses that were replayed from the store
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+const MAX_KEY_LENGTH: usize = 64;
+/// Larger responses are passed through unsaved, releasing the key
+const MAX_SAVED_RESPONSE_BYTES: usize = 1024 * 1024;
+/// After this long without a response, a claim may be taken over by a retry
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+/// Saved responses are replayed for this long
+const KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotencyKey(String);
+
+impl TryFrom<&HeaderValue> for IdempotencyKey {
+    type Error = &'static str;
+
+    fn try_from(value: &HeaderValue) -> Result<Self, Self::Error> {
+        let key = value
+            .to_str()
+            .map_err(|_| "Idempotency key must be visible ASCII")?;
+
+        if key.is_empty() {
+            return Err("Idempotency key cannot be empty");
+        }
+
+        if key.len() > MAX_KEY_LENGTH {
+            return Err("Idempotency key is too long (maximum 64 characters)");
+        }
+
+        Ok(Self(key.to_string()))
+    }
+}
+
+impl IdempotencyKey {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+enum NextAction {
+    /// Carries the claim time, which identifies this claim from now on
+    StartProcessing(DateTime<Utc>),
+    ReturnSavedResponse(Response),
+    InProgress,
+    KeyReused,
+}
+
+/// Middleware enforcing idempotency on POST/PUT/PATCH/DELETE requests that
+/// carry an `Idempotency-Key` header. Requests without the header pass through.
+pub async fn idempotency(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(user_id) = idempotency_scope(&request) else {
+        return next.run(request).await;
+    };
+    if !is_state_changing(request.method()) {
+        return next.run(request).await;
+    }
+
+    let key = match request.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        None => return next.run(request).await,
+        Some(value) => match IdempotencyKey::try_from(value) {
+            Ok(key) => key,
+            Err(message) => {
+                return ApiError::bad_request(
+                    "invalid-idempotency-key",
+                    "The Idempotency-Key header is invalid",
+                )
+                .with_detail(message)
+                .into_response();
+            }
+        },
+    };
+
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let max_body_bytes = max_body_bytes(&state.form_limits);
+    let (request, fingerprint) = match fingerprint(request, max_body_bytes).await {
+        Ok(fingerprinted) => fingerprinted,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let claim = Claim {
+        user_id,
+        key: &key,
+        method: &method,
+        path: &path,
+        fingerprint: &fingerprint,
+    };
+
+    let claimed_at = match try_claim(&state.db, &claim).await {
+        Ok(NextAction::StartProcessing(claimed_at)) => claimed_at,
+        Ok(NextAction::ReturnSavedResponse(response)) => return response,
+        Ok(NextAction::InProgress) => {
+            return ApiError::new(
+                StatusCode::CONFLICT,
+                "idempotency-key-in-progress",
+                "A request with this idempotency key is still being processed",
+            )
+            .into_response();
+        }
+        Ok(NextAction::KeyReused) => {
+            return ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "idempotency-key-reused",
+                "Idempotency key was already used for a different request",
+            )
+            .into_response();
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to claim idempotency key");
+            return ApiError::internal().into_response();
+        }
+    };
+
+    let response = next.run(request).await;
+
+    // Server errors are not final: release the key so the client can retry
+    if response.status().is_server_error() {
+        if let Err(e) = release(&state.db, user_id, &key, claimed_at).await {
+            tracing::error!(error = %e, "Failed to release idempotency key");
+        }
+        return response;
+    }
+
+    match save_response(&state.db, user_id, &key, claimed_at, response).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to save idempotent response");
+            ApiError::internal().into_response()
+        }
+    }
+}
+
+fn is_state_changing(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    )
+}
+
+/// Whose keys these are: the authenticated user, if any
+fn idempotency_scope(request: &Request) -> Option<Uuid> {
+    request.extensions().get::<UserId>().map(|user| user.0)
+}
+
+/// What a request claims its key with
+struct Claim<'a> {
+    user_id: Uuid,
+    key: &'a IdempotencyKey,
+    method: &'a str,
+    path: &'a str,
+    fingerprint: &'a [u8],
+}
+
+/// Bodies are buffered to fingerprint them, up to the most any idempotent
+/// route accepts: the newsletter limit, or the default form limit if an
+/// operator raised it above that
+fn max_body_bytes(defaults: &StrictFormLimits) -> usize {
+    NEWSLETTER_MAX_BODY_BYTES.max(defaults.max_body_bytes)
+}
+
+/// Buffer the body and hash it together with the query string, so a key
+/// reused with a different payload is caught
+async fn fingerprint(
+    request: Request,
+    max_body_bytes: usize,
+) -> Result<(Request, Vec<u8>), ApiError> {
+    let (parts, body) = request.into_parts();
+    let body = to_bytes(body, max_body_bytes).await.map_err(|_| {
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload-too-large",
+            "The request body is too large",
+        )
+    })?;
+
+    let query = parts.uri.query().unwrap_or("");
+    let mut hasher = Sha256::new();
+    hasher.update((query.len() as u64).to_be_bytes());
+    hasher.update(query);
+    hasher.update(&body);
+    let fingerprint = hasher.finalize().to_vec();
+
+    Ok((Request::from_parts(parts, Body::from(body)), fingerprint))
+}
+
+async fn try_claim(pool: &PgPool, claim: &Claim<'_>) -> Result<NextAction, sqlx::Error> {
+    let ttl_secs = KEY_TTL.as_secs_f64();
+    let claim_timeout_secs = CLAIM_TIMEOUT.as_secs_f64();
+
+    // An expired key is free again, even before the next sweep
+    sqlx::query!(
+        r#"
+            DELETE FROM idempotency
+            WHERE user_id = $1 AND idempotency_key = $2
+              AND created_at < now() - make_interval(secs => $3)
+        "#,
+        claim.user_id,
+        claim.key.as_str(),
+        ttl_secs
+    )
+    .execute(pool)
+    .await?;
+
+    let inserted = sqlx::query_scalar!(
+        r#"
+            INSERT INTO idempotency (
+                user_id, idempotency_key, request_method, request_path,
+                request_fingerprint, created_at
+            )
+            VALUES ($1, $2, $3, $4, $5, now())
+            ON CONFLICT DO NOTHING
+            RETURNING created_at
+        "#,
+        claim.user_id,
+        claim.key.as_str(),
+        claim.method,
+        claim.path,
+        claim.fingerprint
+    )
+    .fetch_optional(pool)
+    .await?;
+    if let Some(claimed_at) = inserted {
+        return Ok(NextAction::StartProcessing(claimed_at));
+    }
+
+    // Take over a claim whose request never finished, if this is its retry
+    let taken_over = sqlx::query_scalar!(
+        r#"
+            UPDATE idempotency
+            SET created_at = now()
+            WHERE user_id = $1 AND idempotency_key = $2
+              AND request_method = $3 AND request_path = $4
+              AND request_fingerprint = $5
+              AND response_status_code IS NULL
+              AND created_at < now() - make_interval(secs => $6)
+            RETURNING created_at
+        "#,
+        claim.user_id,
+        claim.key.as_str(),
+        claim.method,
+        claim.path,
+        claim.fingerprint,
+        claim_timeout_secs
+    )
+    .fetch_optional(pool)
+    .await?;
+    if let Some(claimed_at) = taken_over {
+        tracing::warn!("Taking over an abandoned idempotency key claim");
+        return Ok(NextAction::StartProcessing(claimed_at));
+    }
+
+    let saved = sqlx::query!(
+        r#"
+            SELECT request_method, request_path, request_fingerprint,
+                   response_status_code,
+                   response_header_names,
+                   response_header_values,
+                   response_body
+            FROM idempotency
+            WHERE user_id = $1 AND idempotency_key = $2
+        "#,
+        claim.user_id,
+        claim.key.as_str()
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    // The first request failed and released the key in the meantime
+    let Some(saved) = saved else {
+        return Ok(NextAction::InProgress);
+    };
+
+    if saved.request_method != claim.method
+        || saved.request_path != claim.path
+        || saved.request_fingerprint != claim.fingerprint
+    {
+        return Ok(NextAction::KeyReused);
+    }
+
+    let (Some(status), Some(names), Some(values), Some(body)) = (
+        saved.response_status_code,
+        saved.response_header_names,
+        saved.response_header_values,
+        saved.response_body,
+    ) else {
+        return Ok(NextAction::InProgress);
+    };
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() =
+        StatusCode::from_u16(status as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let headers = response.headers_mut();
+    for (name, value) in names.into_iter().zip(values) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::from_bytes(&value))
+        {
+            headers.append(name, value);
+        }
+    }
+    headers.insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+
+    Ok(NextAction::ReturnSavedResponse(response))
+}
+
+/// Give up our claim, unless a retry has taken it over already
+async fn release(
+    pool: &PgPool,
+    user_id: Uuid,
+    key: &IdempotencyKey,
+    claimed_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            DELETE FROM idempotency
+            WHERE user_id = $1 AND idempotency_key = $2 AND created_at = $3
+        "#,
+        user_id,
+        key.as_str(),
+        claimed_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Persist the response and hand back an equivalent one to send to the client
+async fn save_response(
+    pool: &PgPool,
+    user_id: Uuid,
+    key: &IdempotencyKey,
+    claimed_at: DateTime<Utc>,
+    response: Response,
+) -> Result<Response, anyhow::Error> {
+    let (parts, body) = response.into_parts();
+    let body = match buffer_body(body, MAX_SAVED_RESPONSE_BYTES).await? {
+        Buffered::Complete(body) => body,
+        Buffered::TooLarge(body) => {
+            tracing::warn!("Response too large to save, releasing the idempotency key");
+            if let Err(e) = release(pool, user_id, key, claimed_at).await {
+                tracing::error!(error = %e, "Failed to release idempotency key");
+            }
+            return Ok(Response::from_parts(parts, body));
+        }
+    };
+
+    let status_code = parts.status.as_u16() as i16;
+    let (names, values): (Vec<String>, Vec<Vec<u8>>) = parts
+        .headers
+        .iter()
+        // The request id belongs to the original request, not the replay, and
+        // cookies may carry a session that must only reach the original caller
+        .filter(|(name, _)| name.as_str() != "x-request-id" && *name != header::SET_COOKIE)
+        .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+        .unzip();
+
+    sqlx::query!(
+        r#"
+            UPDATE idempotency
+            SET response_status_code = $3,
+                response_header_names = $4,
+                response_header_values = $5,
+                response_body = $6
+            WHERE user_id = $1 AND idempotency_key = $2 AND created_at = $7
+        "#,
+        user_id,
+        key.as_str(),
+        status_code,
+        &names,
+        &values,
+        body.as_ref(),
+        claimed_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+enum Buffered {
+    Complete(Bytes),
+    /// What was read so far, followed by the rest of the body
+    TooLarge(Body),
+}
+
+/// Buffer `body` unless it turns out to be longer than `limit`
+async fn buffer_body(mut body: Body, limit: usize) -> Result<Buffered, axum::Error> {
+    let mut buffered = Vec::new();
+    while let Some(frame) = body.frame().await {
+        let Ok(data) = frame?.into_data() else {
+            continue;
+        };
+        buffered.extend_from_slice(&data);
+        if buffered.len() > limit {
+            return Ok(Buffered::TooLarge(Body::new(Prefixed {
+                head: Some(buffered.into()),
+                rest: body,
+            })));
+        }
+    }
+    Ok(Buffered::Complete(buffered.into()))
+}
+
+/// A body that yields `head` before the frames of `rest`
+struct Prefixed {
+    head: Option<Bytes>,
+    rest: Body,
+}
+
+impl HttpBody for Prefixed {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        match self.head.take() {
+            Some(head) => Poll::Ready(Some(Ok(Frame::data(head)))),
+            None => Pin::new(&mut self.rest).poll_frame(cx),
+        }
+    }
+}
+
+/// Delete keys older than `KEY_TTL`, returning how many went
+pub async fn expire_idempotency_keys(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let deleted = sqlx::query!(
+        r#"DELETE FROM idempotency WHERE created_at < now() - make_interval(secs => $1)"#,
+        KEY_TTL.as_secs_f64()
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(deleted)
+}
+
+/// Sweep expired keys every `EXPIRY_INTERVAL` until `shutdown` is cancelled
+pub async fn run_expiry_until_stopped(pool: PgPool, shutdown: CancellationToken) {
+    loop {
+        tokio::select! {
+            () = tokio::time::sleep(EXPIRY_INTERVAL) => {}
+            () = shutdown.cancelled() => return,
+        }
+        match expire_idempotency_keys(&pool).await {
+            Ok(deleted) => tracing::debug!(deleted, "Expired idempotency keys"),
+            Err(e) => tracing::warn!(error = %e, "Failed to expire idempotency keys"),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! src/issue_delivery_worker.rs
+//! Background worker draining `issue_delivery_queue`.
+//!
+//! Each task is claimed with `FOR UPDATE SKIP LOCKED` inside a transaction
+//! that stays open until the email is sent, so any number of app instances
+//! can work the queue side by side without delivering an issue twice.
+use std::time::Duration;
+
+use sqlx::{Postgres, Transaction};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::email_client::EmailHeader;
+use crate::routes::AppState;
+use crate::routes::subscriptions::SubscriberEmail;
+use crate::subscription_status::SubscriptionStatus;
+use crate::unsubscribe_token::unsubscribe_url;
+
+/// Attempts per (issue, subscriber) before the delivery is dropped
+const MAX_RETRIES: i16 = 5;
+/// Delay before the first retry, doubled on every further attempt
+const RETRY_BASE_DELAY_SECS: f64 = 30.0;
+
+const EMPTY_QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+const ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+struct DeliveryTask {
+    newsletter_issue_id: Uuid,
+    subscriber_id: Uuid,
+    n_retries: i16,
+    email: String,
+    status: SubscriptionStatus,
+}
+
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+/// Deliver issues until `shutdown` is cancelled. A task that has already
+/// been claimed is finished first; only the waits in between are cut short.
+pub async fn run_worker_until_stopped(state: AppState, shutdown: CancellationToken) {
+    while !shutdown.is_cancelled() {
+        // Nothing to do until a degraded start has reached the database
+        if !state.readiness.is_ready() {
+            pause(ERROR_BACKOFF, &shutdown).await;
+            continue;
+        }
+        match try_execute_task(&state).await {
+            Ok(ExecutionOutcome::EmptyQueue) => pause(EMPTY_QUEUE_POLL_INTERVAL, &shutdown).await,
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+            Err(e) => {
+                tracing::error!(error = ?e, "Issue delivery worker failed to execute a task");
+                pause(ERROR_BACKOFF, &shutdown).await;
+            }
+        }
+    }
+    tracing::info!("Issue delivery worker stopped");
+}
+
+async fn pause(duration: Duration, shutdown: &CancellationToken) {
+    tokio::select! {
+        () = tokio::time::sleep(duration) => {}
+        () = shutdown.cancelled() => {}
+    }
+}
+
+/// Claim and process a single delivery task
+pub async fn try_execute_task(state: &AppState) -> Result<ExecutionOutcome, anyhow::Error> {
+    let mut transaction = state.db.begin().await?;
+    let Some(task) = dequeue_task(&mut transaction).await? else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+
+    // Subscriber may have left the list after the issue was queued
+    if !task.status.receives_newsletters() {
+        delete_task(&mut transaction, &task).await?;
+        transaction.commit().await?;
+        return Ok(ExecutionOutcome::TaskCompleted);
+    }
+
+    let email = match SubscriberEmail::try_from(task.email.clone()) {
+        Ok(email) => email,
+        Err(e) => {
+            tracing::warn!(
+                error = %e,
+                subscriber_id = %task.subscriber_id,
+                "Dropping delivery to invalid stored email"
+            );
+            delete_task(&mut transaction, &task).await?;
+            transaction.commit().await?;
+            return Ok(ExecutionOutcome::TaskCompleted);
+        }
+    };
+
+    let issue = get_issue(&mut transaction, task.newsletter_issue_id).await?;
+    let unsubscribe_link = unsubscribe_url(
+        &state.base_url,
+        state.hmac_secret.expose_secret(),
+        task.subscriber_id,
+    );
+
+    match state
+        .email
+        .send_email(
+            email,
+            issue.title,
+            issue.html_content,
+            issue.text_content,
+            &EmailHeader::list_unsubscribe(&unsubscribe_link),
+        )
+        .await
+    {
+        Ok(()) => delete_task(&mut transaction, &task).await?,
+        Err(e) if task.n_retries + 1 >= MAX_RETRIES => {
+            tracing::error!(
+                error = %e,
+                newsletter_issue_id = %task.newsletter_issue_id,
+                subscriber_id = %task.subscriber_id,
+                "Giving up on newsletter delivery"
+            );
+            delete_task(&mut transaction, &task).await?;
+        }
+        Err(e) => {
+            tracing::warn!(
+                error = %e,
+                newsletter_issue_id = %task.newsletter_issue_id,
+                subscriber_id = %task.subscriber_id,
+                n_retries = task.n_retries,
+                "Newsletter delivery failed, will retry"
+            );
+            schedule_retry(&mut transaction, &task).await?;
+        }
+    }
+
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+async fn dequeue_task(
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<DeliveryTask>, sqlx::Error> {
+    let task = sqlx::query_as!(
+        DeliveryTask,
+        r#"
+            SELECT q.newsletter_issue_id, q.subscriber_id, q.n_retries,
+                   s.email, s.status AS "status: SubscriptionStatus"
+            FROM issue_delivery_queue q
+            JOIN subscriptions s ON s.id = q.subscriber_id
+            WHERE q.execute_after <= now()
+            ORDER BY q.execute_after
+            LIMIT 1
+            FOR UPDATE OF q
+            SKIP LOCKED
+        "#
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+    Ok(task)
+}
+
+async fn delete_task(
+    transaction: &mut Transaction<'_, Postgres>,
+    task: &DeliveryTask,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            DELETE FROM issue_delivery_queue
+            WHERE newsletter_issue_id = $1 AND subscriber_id = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+async fn schedule_retry(
+    transaction: &mut Transaction<'_, Postgres>,
+    task: &DeliveryTask,
+) -> Result<(), sqlx::Error> {
+    let delay_secs = RETRY_BASE_DELAY_SECS * 2f64.powi(task.n_retries.into());
+    sqlx::query!(
+        r#"
+            UPDATE issue_delivery_queue
+            SET n_retries = n_retries + 1,
+                execute_after = now() + make_interval(secs => $3)
+            WHERE newsletter_issue_id = $1 AND subscriber_id = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_id,
+        delay_secs
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+async fn get_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<NewsletterIssue, sqlx::Error> {
+    sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+            SELECT title, text_content, html_content
+            FROM newsletter_issues
+            WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_one(&mut **transaction)
+    .await
+}
@@ -1,5 +1,20 @@
+pub mod authentication;
 pub mod configuration;
 pub mod email_client;
+pub mod error;
+pub mod form_deserializer;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod password_reset;
 pub mod routes;
+pub mod secret;
+pub mod session;
 pub mod startup;
+pub mod strict_body;
 pub mod strict_form;
+pub mod strict_json;
+pub mod strict_multipart;
+pub mod strict_query;
+pub mod subscription_status;
+pub mod unsubscribe_token;
+pub mod urlencoded;
@@ -1,13 +1,15 @@
 use sqlx::PgPool;
-use sqlx::migrate::Migrator;
 use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{EnvFilter, util::SubscriberInitExt};
 
-use incosense::configuration::Settings;
-use incosense::email_client::EmailClient;
-use incosense::startup::run;
-
-static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+use incosense::authentication::{Credentials, ensure_admin_user};
+use incosense::configuration::{AdminSettings, Settings};
+use incosense::email_client::{EmailClient, ProviderStatus};
+use incosense::routes::{AppState, Readiness};
+use incosense::startup::{Backoff, MIGRATOR, run, shutdown_signal, wait_for_database};
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
@@ -22,25 +24,116 @@ async fn main() -> std::io::Result<()> {
         .finish()
         .init();
 
-    // Settings::from_env() now returns Settings directly
-    let configuration = Settings::from_env();
+    // Every configuration problem is listed before giving up
+    let configuration = Settings::load().unwrap_or_else(|e| {
+        tracing::error!("{e}");
+        std::process::exit(1);
+    });
 
-    let connection_pool = PgPool::connect(&configuration.database.connection_string())
-        .await
-        .expect("Failed to connect to Postgres.");
+    // Connects on first use, i.e. in wait_for_database below
+    let connection_pool = configuration.database.connect_lazy();
+    let retry = configuration.database.connect_retry;
 
     let email_client = EmailClient {
-        sender: configuration.email_settings.sender_email,
+        sender: configuration.email_settings.sender,
         url: configuration.email_settings.service_url,
         token: configuration.email_settings.api_token,
+        status: ProviderStatus::default(),
+    };
+
+    // SIGINT/SIGTERM start a graceful shutdown, even while still starting up
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.cancel();
+        }
+    });
+
+    let readiness = if configuration.database.start_degraded {
+        // Serve right away and report not ready until the database is set up
+        let readiness = Readiness::not_ready();
+        let pool = connection_pool.clone();
+        let admin = configuration.admin;
+        let ready = readiness.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            // Preparing can fail too, e.g. if the database drops right after
+            // answering; start over with the same backoff until it works
+            let mut backoff = Backoff::new(retry.initial_backoff(), retry.max_backoff());
+            loop {
+                tokio::select! {
+                    result = wait_for_database(&pool, &retry, None) => {
+                        result.expect("Retrying without a deadline never fails");
+                    }
+                    () = shutdown.cancelled() => return,
+                }
+                let error = match prepare_database(&pool, admin.as_ref()).await {
+                    Ok(()) => {
+                        ready.mark_ready();
+                        return;
+                    }
+                    Err(e) => e,
+                };
+                let delay = backoff.next().expect("backoff never ends");
+                tracing::error!(
+                    error = %error,
+                    retry_in_ms = delay.as_millis() as u64,
+                    "Failed to prepare the database"
+                );
+                tokio::select! {
+                    () = tokio::time::sleep(delay) => {}
+                    () = shutdown.cancelled() => return,
+                }
+            }
+        });
+        readiness
+    } else {
+        tokio::select! {
+            result = wait_for_database(&connection_pool, &retry, Some(retry.max_wait())) => {
+                result.map_err(std::io::Error::other)?;
+            }
+            () = shutdown.cancelled() => return Ok(()),
+        }
+        prepare_database(&connection_pool, configuration.admin.as_ref()).await?;
+        Readiness::ready()
     };
-    // Run pending migrations automatically
-    MIGRATOR
-        .run(&connection_pool)
-        .await
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
+    let app_state = AppState {
+        db: connection_pool,
+        email: email_client,
+        base_url: configuration.base_url,
+        hmac_secret: configuration.hmac_secret,
+        form_limits: configuration.form_limits,
+        readiness,
+    };
     let bind_addr: SocketAddr = ([0, 0, 0, 0], configuration.application_port).into();
-    run(Some(bind_addr), connection_pool, email_client).await?;
+    let listener = TcpListener::bind(bind_addr).await?;
+    run(
+        listener,
+        app_state,
+        shutdown,
+        Duration::from_secs(configuration.shutdown_timeout_secs),
+    )
+    .await
+}
+
+/// Run pending migrations and create the configured admin account
+async fn prepare_database(pool: &PgPool, admin: Option<&AdminSettings>) -> std::io::Result<()> {
+    MIGRATOR.run(pool).await.map_err(std::io::Error::other)?;
+
+    if let Some(admin) = admin {
+        ensure_admin_user(
+            pool,
+            Credentials {
+                username: admin.username.clone(),
+                password: admin.password.clone(),
+            },
+            admin.email.clone(),
+        )
+        .await
+        .map_err(std::io::Error::other)?;
+    }
     Ok(())
 }
@@ -0,0 +1,113 @@
+//! src/password_reset.rs
+//! Time-limited, single-use tokens for the forgotten-password flow.
+//! Tokens are hashed at rest like session ids. Used and expired tokens are
+//! swept every `EXPIRY_INTERVAL`.
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rand::{RngCore, thread_rng};
+use sha2::{Digest, Sha256};
+use sqlx::{PgExecutor, PgPool};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// Reset links stop working after an hour
+pub const RESET_TOKEN_TTL_SECS: f64 = 60.0 * 60.0;
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Issue a new token for `user_id`, revoking any older unused ones
+pub async fn create_reset_token(pool: &PgPool, user_id: Uuid) -> Result<String, sqlx::Error> {
+    let mut bytes = [0u8; 32];
+    thread_rng().fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        r#"DELETE FROM password_reset_tokens WHERE user_id = $1 AND used_at IS NULL"#,
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+            INSERT INTO password_reset_tokens (token_hash, user_id, created_at, expires_at)
+            VALUES ($1, $2, now(), now() + make_interval(secs => $3))
+        "#,
+        hash_token(&token),
+        user_id,
+        RESET_TOKEN_TTL_SECS
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(token)
+}
+
+/// User the token belongs to, if it is still usable. Doesn't consume it.
+pub async fn peek_reset_token(
+    executor: impl PgExecutor<'_>,
+    token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let user_id = sqlx::query!(
+        r#"
+            SELECT user_id FROM password_reset_tokens
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+        "#,
+        hash_token(token)
+    )
+    .fetch_optional(executor)
+    .await?
+    .map(|row| row.user_id);
+    Ok(user_id)
+}
+
+/// Mark the token as used. Returns its user only for the first caller,
+/// so a token can't be redeemed twice even by concurrent requests.
+pub async fn consume_reset_token(
+    executor: impl PgExecutor<'_>,
+    token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let user_id = sqlx::query!(
+        r#"
+            UPDATE password_reset_tokens
+            SET used_at = now()
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+            RETURNING user_id
+        "#,
+        hash_token(token)
+    )
+    .fetch_optional(executor)
+    .await?
+    .map(|row| row.user_id);
+    Ok(user_id)
+}
+
+/// Delete tokens that can no longer be redeemed, returning how many went
+pub async fn expire_reset_tokens(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let deleted = sqlx::query!(
+        r#"DELETE FROM password_reset_tokens WHERE used_at IS NOT NULL OR expires_at <= now()"#
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(deleted)
+}
+
+/// Sweep dead tokens every `EXPIRY_INTERVAL` until `shutdown` is cancelled
+pub async fn run_expiry_until_stopped(pool: PgPool, shutdown: CancellationToken) {
+    loop {
+        tokio::select! {
+            () = tokio::time::sleep(EXPIRY_INTERVAL) => {}
+            () = shutdown.cancelled() => return,
+        }
+        match expire_reset_tokens(&pool).await {
+            Ok(deleted) => tracing::debug!(deleted, "Expired password reset tokens"),
+            Err(e) => tracing::warn!(error = %e, "Failed to expire password reset tokens"),
+        }
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
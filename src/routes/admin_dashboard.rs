@@ -0,0 +1,60 @@
+use askama::Template;
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use hyper::StatusCode;
+
+use crate::routes::{AppState, render_html};
+use crate::session::{FlashMessage, take_flash};
+use crate::subscription_status::SubscriptionStatus;
+
+#[derive(Template)]
+#[template(path = "admin/dashboard.html")]
+struct DashboardTemplate {
+    flash: Option<FlashMessage>,
+    counts: Vec<(SubscriptionStatus, i64)>,
+    total: i64,
+}
+
+/// Subscriber counts per status
+pub async fn admin_dashboard(State(state): State<AppState>, jar: CookieJar) -> Response {
+    let rows = match sqlx::query!(
+        r#"
+            SELECT status AS "status: SubscriptionStatus", count(*) AS "count!"
+            FROM subscriptions
+            GROUP BY status
+        "#
+    )
+    .fetch_all(&state.db)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    // Statuses without subscribers still get a row
+    let counts: Vec<(SubscriptionStatus, i64)> = SubscriptionStatus::ALL
+        .into_iter()
+        .map(|status| {
+            let count = rows
+                .iter()
+                .find(|row| row.status == status)
+                .map_or(0, |row| row.count);
+            (status, count)
+        })
+        .collect();
+    let total = counts.iter().map(|(_, count)| count).sum();
+
+    let (jar, flash) = take_flash(jar);
+    (
+        jar,
+        render_html(&DashboardTemplate {
+            flash,
+            counts,
+            total,
+        }),
+    )
+        .into_response()
+}
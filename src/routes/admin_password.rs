@@ -0,0 +1,119 @@
+use askama::Template;
+use axum::{
+    Extension,
+    extract::State,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use hyper::StatusCode;
+use serde::Deserialize;
+
+use crate::authentication::{
+    AuthError, Credentials, PasswordPolicyViolation, UserId, change_password as store_password,
+    check_password_policy, get_username, validate_credentials,
+};
+use crate::routes::{AppState, render_html};
+use crate::secret::Secret;
+use crate::session::{
+    FlashMessage, delete_user_sessions, remove_session_cookie, set_flash, take_flash,
+};
+use crate::strict_form::StrictForm;
+
+#[derive(Template)]
+#[template(path = "admin/password.html")]
+struct ChangePasswordTemplate {
+    flash: Option<FlashMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordForm {
+    current_password: Secret<String>,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+pub async fn change_password_form(jar: CookieJar) -> impl IntoResponse {
+    let (jar, flash) = take_flash(jar);
+    (jar, render_html(&ChangePasswordTemplate { flash }))
+}
+
+impl From<PasswordPolicyViolation> for FlashMessage {
+    fn from(violation: PasswordPolicyViolation) -> Self {
+        match violation {
+            PasswordPolicyViolation::TooShort => FlashMessage::PasswordTooShort,
+            PasswordPolicyViolation::TooLong => FlashMessage::PasswordTooLong,
+            PasswordPolicyViolation::ContainsUsername => FlashMessage::PasswordContainsUsername,
+        }
+    }
+}
+
+/// Change the logged-in admin's password. Every session of the account,
+/// including the current one, ends afterwards.
+pub async fn change_password(
+    State(state): State<AppState>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    jar: CookieJar,
+    StrictForm(form): StrictForm<ChangePasswordForm>,
+) -> Response {
+    let secure = state.secure_cookies();
+    let retry = |jar: CookieJar, flash: FlashMessage| {
+        (
+            set_flash(jar, flash, secure),
+            Redirect::to("/admin/password"),
+        )
+            .into_response()
+    };
+
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        return retry(jar, FlashMessage::PasswordMismatch);
+    }
+
+    let username = match get_username(&state.db, user_id).await {
+        Ok(username) => username,
+        Err(e) => {
+            tracing::error!(error = ?e, "Failed to look up username");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    if let Err(violation) = check_password_policy(&username, form.new_password.expose_secret()) {
+        return retry(jar, violation.into());
+    }
+
+    if form.new_password.expose_secret() == form.current_password.expose_secret() {
+        return retry(jar, FlashMessage::PasswordUnchanged);
+    }
+
+    let credentials = Credentials {
+        username,
+        password: form.current_password,
+    };
+    match validate_credentials(&state.db, credentials).await {
+        Ok(_) => {}
+        Err(AuthError::InvalidCredentials) => {
+            return retry(jar, FlashMessage::CurrentPasswordWrong);
+        }
+        Err(AuthError::Unexpected(e)) => {
+            tracing::error!(error = ?e, "Failed to validate credentials");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    // The new password and the end of the old sessions go together
+    let result: Result<(), anyhow::Error> = async {
+        let mut transaction = state.db.begin().await?;
+        store_password(&mut *transaction, user_id, form.new_password).await?;
+        delete_user_sessions(&mut *transaction, user_id).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        tracing::error!(error = ?e, "Failed to change password");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    let jar = remove_session_cookie(jar);
+    let jar = set_flash(jar, FlashMessage::PasswordChanged, secure);
+    (jar, Redirect::to("/login")).into_response()
+}
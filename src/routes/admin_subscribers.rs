@@ -0,0 +1,75 @@
+use askama::Template;
+use axum::{
+    Json,
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use hyper::StatusCode;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::routes::{AppState, accepts_html, render_html};
+use crate::session::{FlashMessage, take_flash};
+use crate::subscription_status::SubscriptionStatus;
+
+#[derive(Debug, Serialize)]
+pub struct SubscriberEntry {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: SubscriptionStatus,
+    pub subscribed_at: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin/subscribers.html")]
+struct SubscribersTemplate {
+    flash: Option<FlashMessage>,
+    subscribers: Vec<SubscriberEntry>,
+}
+
+/// Every subscriber, newest first.
+/// Browsers get an HTML table, API clients get JSON.
+pub async fn list_subscribers(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    jar: CookieJar,
+) -> Response {
+    let rows = match sqlx::query!(
+        r#"
+            SELECT id, email, name, status AS "status: SubscriptionStatus", subscribed_at
+            FROM subscriptions
+            ORDER BY subscribed_at DESC
+        "#
+    )
+    .fetch_all(&state.db)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let subscribers: Vec<SubscriberEntry> = rows
+        .into_iter()
+        .map(|row| SubscriberEntry {
+            id: row.id,
+            email: row.email,
+            name: row.name,
+            status: row.status,
+            subscribed_at: row.subscribed_at.to_rfc3339(),
+        })
+        .collect();
+
+    if accepts_html(&headers) {
+        let (jar, flash) = take_flash(jar);
+        (
+            jar,
+            render_html(&SubscribersTemplate { flash, subscribers }),
+        )
+            .into_response()
+    } else {
+        Json(subscribers).into_response()
+    }
+}
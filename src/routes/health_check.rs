@@ -1,9 +1,259 @@
+use axum::Json;
+use axum::extract::State;
 use axum::response::IntoResponse;
 use hyper::StatusCode;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use tracing::info;
 
-// Build a function that returns an HTTP Response 200 OK with an empty body
-pub async fn healthcheck() -> impl IntoResponse {
+use crate::email_client::ProviderStatus;
+use crate::routes::AppState;
+use crate::startup::MIGRATOR;
+
+/// Version of the running build
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Commit the running build was made from, set by build.rs
+pub const GIT_COMMIT: &str = env!("GIT_COMMIT");
+
+/// Upper bound on each database check, so a hung connection fails the
+/// probe instead of outlasting the orchestrator's own timeout
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether the app can serve traffic. It starts out not ready when the
+/// process boots in degraded mode, until the database has been reached
+/// and migrated.
+#[derive(Debug, Clone)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn ready() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+
+    pub fn not_ready() -> Self {
+        Self(Arc::new(AtomicBool::new(false)))
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn mark_ready(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+// 200 OK with an empty body once ready, 503 before that
+pub async fn healthcheck(State(state): State<AppState>) -> impl IntoResponse {
     info!("Handling health_check request");
-    StatusCode::OK
+    if state.readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CheckStatus {
+    Up,
+    Down,
+    Unknown,
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u128>,
+    /// When the status was observed, for checks that are not run per request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checked_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Checks {
+    startup: Check,
+    database: Check,
+    migrations: Check,
+    email: Check,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum OverallStatus {
+    /// Every check is up
+    Ready,
+    /// Able to serve, but the email provider is down or has not been used yet
+    Degraded,
+    NotReady,
+}
+
+#[derive(Debug, Serialize)]
+struct HealthReport<T> {
+    status: T,
+    version: &'static str,
+    commit: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checks: Option<Checks>,
+}
+
+impl Check {
+    fn up(latency: Duration) -> Self {
+        Self {
+            status: CheckStatus::Up,
+            latency_ms: Some(latency.as_millis()),
+            checked_at: None,
+            detail: None,
+        }
+    }
+
+    fn down(latency: Duration, detail: impl Into<String>) -> Self {
+        Self {
+            status: CheckStatus::Down,
+            latency_ms: Some(latency.as_millis()),
+            checked_at: None,
+            detail: Some(detail.into()),
+        }
+    }
+
+    fn is_up(&self) -> bool {
+        matches!(self.status, CheckStatus::Up)
+    }
+}
+
+/// 200 as long as the process can answer; touches nothing else
+pub async fn live() -> impl IntoResponse {
+    Json(HealthReport {
+        status: "up",
+        version: VERSION,
+        commit: GIT_COMMIT,
+        checks: None,
+    })
+}
+
+/// 200 when the app can serve requests, 503 otherwise, with the result of
+/// every check. An email provider outage degrades the app but leaves it
+/// ready: every instance shares the provider, so pulling them all out of
+/// rotation would not help.
+pub async fn ready(State(state): State<AppState>) -> impl IntoResponse {
+    let (database, migrations) =
+        tokio::join!(check_database(&state.db), check_migrations(&state.db));
+    let checks = Checks {
+        startup: check_startup(&state.readiness),
+        database,
+        migrations,
+        email: check_email(&state.email.status),
+    };
+
+    let (code, status) =
+        if !(checks.startup.is_up() && checks.database.is_up() && checks.migrations.is_up()) {
+            (StatusCode::SERVICE_UNAVAILABLE, OverallStatus::NotReady)
+        } else if checks.email.is_up() {
+            (StatusCode::OK, OverallStatus::Ready)
+        } else {
+            (StatusCode::OK, OverallStatus::Degraded)
+        };
+    (
+        code,
+        Json(HealthReport {
+            status,
+            version: VERSION,
+            commit: GIT_COMMIT,
+            checks: Some(checks),
+        }),
+    )
+}
+
+fn check_startup(readiness: &Readiness) -> Check {
+    if readiness.is_ready() {
+        Check::up(Duration::ZERO)
+    } else {
+        Check::down(Duration::ZERO, "The database has not been prepared yet")
+    }
+}
+
+/// Round trip to the database through the pool
+async fn check_database(pool: &PgPool) -> Check {
+    let started = Instant::now();
+    match tokio::time::timeout(
+        CHECK_TIMEOUT,
+        sqlx::query!("SELECT 1 AS ping").fetch_one(pool),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Check::up(started.elapsed()),
+        Ok(Err(e)) => {
+            tracing::warn!(error = %e, "Readiness check could not reach the database");
+            Check::down(started.elapsed(), "The database could not be reached")
+        }
+        Err(_) => Check::down(started.elapsed(), "Timed out"),
+    }
+}
+
+/// Every migration the binary knows about has been applied successfully
+async fn check_migrations(pool: &PgPool) -> Check {
+    let started = Instant::now();
+    let applied = tokio::time::timeout(
+        CHECK_TIMEOUT,
+        sqlx::query_scalar!("SELECT version FROM _sqlx_migrations WHERE success").fetch_all(pool),
+    )
+    .await;
+    let applied: HashSet<i64> = match applied {
+        Ok(Ok(versions)) => versions.into_iter().collect(),
+        Ok(Err(e)) => {
+            tracing::warn!(error = %e, "Readiness check could not list applied migrations");
+            return Check::down(started.elapsed(), "Applied migrations could not be listed");
+        }
+        Err(_) => return Check::down(started.elapsed(), "Timed out"),
+    };
+
+    let pending: Vec<String> = MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .filter(|migration| !applied.contains(&migration.version))
+        .map(|migration| migration.version.to_string())
+        .collect();
+    if pending.is_empty() {
+        Check::up(started.elapsed())
+    } else {
+        Check::down(
+            started.elapsed(),
+            format!("Pending migrations: {}", pending.join(", ")),
+        )
+    }
+}
+
+/// Last known status of the email provider; probing it here would cost an
+/// API call per probe. The error itself was logged by whoever sent the
+/// email, and may name hosts, so it is left out.
+fn check_email(status: &ProviderStatus) -> Check {
+    let Some(call) = status.last_call() else {
+        return Check {
+            status: CheckStatus::Unknown,
+            latency_ms: None,
+            checked_at: None,
+            detail: Some("No email has been sent yet".to_string()),
+        };
+    };
+    let (status, detail) = match (call.reachable, call.error) {
+        (true, None) => (CheckStatus::Up, None),
+        (true, Some(_)) => (CheckStatus::Up, Some("The last email was rejected")),
+        (false, _) => (
+            CheckStatus::Down,
+            Some("The email provider could not be reached"),
+        ),
+    };
+    Check {
+        status,
+        latency_ms: Some(call.latency.as_millis()),
+        checked_at: Some(call.at.to_rfc3339()),
+        detail: detail.map(str::to_string),
+    }
 }
@@ -0,0 +1,93 @@
+use askama::Template;
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use hyper::StatusCode;
+use serde::Deserialize;
+
+use crate::authentication::{AuthError, Credentials, validate_credentials};
+use crate::routes::{AppState, render_html};
+use crate::secret::Secret;
+use crate::session::{
+    FlashMessage, create_session, delete_session, remove_session_cookie, session_cookie,
+    session_id, set_flash, take_flash,
+};
+use crate::strict_form::StrictForm;
+
+/// Where a successful login lands
+const ADMIN_HOME: &str = "/admin/dashboard";
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: Secret<String>,
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginTemplate {
+    flash: Option<FlashMessage>,
+}
+
+pub async fn login_form(jar: CookieJar) -> impl IntoResponse {
+    let (jar, flash) = take_flash(jar);
+    (jar, render_html(&LoginTemplate { flash }))
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    StrictForm(form): StrictForm<LoginForm>,
+) -> Response {
+    let credentials = Credentials {
+        username: form.username,
+        password: form.password,
+    };
+    let secure = state.secure_cookies();
+
+    let user_id = match validate_credentials(&state.db, credentials).await {
+        Ok(user_id) => user_id,
+        Err(AuthError::InvalidCredentials) => {
+            let jar = set_flash(jar, FlashMessage::LoginFailed, secure);
+            return (jar, Redirect::to("/login")).into_response();
+        }
+        Err(AuthError::Unexpected(e)) => {
+            tracing::error!(error = ?e, "Failed to validate credentials");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // Never reuse a session id that existed before login (session fixation)
+    if let Some(previous) = session_id(&jar)
+        && let Err(e) = delete_session(&state.db, &previous).await
+    {
+        tracing::error!(error = %e, "Failed to drop previous session");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    match create_session(&state.db, user_id).await {
+        Ok(new_session_id) => {
+            let jar = jar.add(session_cookie(new_session_id, secure));
+            (jar, Redirect::to(ADMIN_HOME)).into_response()
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to create session");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn logout(State(state): State<AppState>, jar: CookieJar) -> Response {
+    if let Some(current) = session_id(&jar)
+        && let Err(e) = delete_session(&state.db, &current).await
+    {
+        tracing::error!(error = %e, "Failed to delete session");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    let jar = remove_session_cookie(jar);
+    let jar = set_flash(jar, FlashMessage::LoggedOut, state.secure_cookies());
+    (jar, Redirect::to("/login")).into_response()
+}
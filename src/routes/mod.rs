@@ -1,10 +1,14 @@
+use askama::Template;
 use axum::{
-    Router,
+    Extension, Router,
     extract::ConnectInfo,
-    http::Request,
+    http::{HeaderMap, Request, StatusCode, header},
+    middleware::{from_fn, from_fn_with_state},
+    response::{Html, IntoResponse, Response},
     routing::{get, post},
 };
 use sqlx::PgPool;
+use tokio_util::sync::CancellationToken;
 use tower_http::{
     request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
     trace::{DefaultOnResponse, TraceLayer},
@@ -13,18 +17,71 @@ use tracing::Span;
 
 use std::net::SocketAddr;
 
+pub mod admin_dashboard;
+pub mod admin_password;
+pub mod admin_subscribers;
 pub mod health_check;
+pub mod login;
+pub mod newsletters;
+pub mod password_reset;
 pub mod subscriptions;
+pub mod subscriptions_confirm;
+pub mod subscriptions_unsubscribe;
 
-use health_check::healthcheck;
+use admin_dashboard::admin_dashboard;
+use admin_password::{change_password, change_password_form};
+use admin_subscribers::list_subscribers;
+pub use health_check::Readiness;
+use health_check::{healthcheck, live, ready};
+use login::{login, login_form, logout};
+use newsletters::{newsletter_form, publish_newsletter};
+use password_reset::{forgot_password, forgot_password_form, reset_password, reset_password_form};
 use subscriptions::post_subscriber;
+use subscriptions_confirm::confirm;
+use subscriptions_unsubscribe::{unsubscribe, unsubscribe_form};
 
+use crate::authentication::require_login;
 use crate::email_client::EmailClient;
+use crate::error::problem_details;
+use crate::idempotency::idempotency;
+use crate::secret::Secret;
+use crate::startup::cancel_on;
+use crate::strict_form::StrictFormLimits;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: PgPool,
     pub email: EmailClient,
+    pub base_url: String,
+    pub hmac_secret: Secret<String>,
+    pub form_limits: StrictFormLimits,
+    pub readiness: Readiness,
+}
+
+impl AppState {
+    /// Cookies get the `Secure` flag unless the app is served over plain http
+    pub fn secure_cookies(&self) -> bool {
+        !self.base_url.starts_with("http://")
+    }
+}
+
+/// Whether the client is a browser asking for a page rather than an API client
+pub fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// Render an askama template into an HTML response
+pub fn render_html<T: Template>(template: &T) -> Response {
+    match template.render() {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to render template");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
 }
 
 /// Create a span for every request, including method, path, and client IP
@@ -59,8 +116,6 @@ fn make_request_span<B>(req: &Request<B>) -> Span {
 
     let query = req.uri().query().unwrap_or("");
 
-    dbg!(req.extensions().get::<tower_http::request_id::RequestId>());
-
     tracing::info_span!(
         "http_request",
         request_id=%request_id,
@@ -72,17 +127,95 @@ fn make_request_span<B>(req: &Request<B>) -> Span {
     )
 }
 
+/// The public subscribe form carries a name and an email, nothing more
+fn subscribe_form_limits(defaults: StrictFormLimits) -> StrictFormLimits {
+    StrictFormLimits {
+        max_body_bytes: 4 * 1024,
+        max_fields: 8,
+        max_value_len: 1024,
+        ..defaults
+    }
+}
+
+/// Newsletter issues carry full HTML bodies
+pub(crate) const NEWSLETTER_MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+fn newsletter_form_limits(defaults: StrictFormLimits) -> StrictFormLimits {
+    StrictFormLimits {
+        max_body_bytes: NEWSLETTER_MAX_BODY_BYTES,
+        max_value_len: 2 * 1024 * 1024,
+        ..defaults
+    }
+}
+
 pub fn build_router(app_state: AppState) -> Router {
+    build_router_with_hard_stop(app_state, CancellationToken::new())
+}
+
+/// `build_router`, with in-flight requests answered by a 503 once
+/// `hard_stop` is cancelled; see `startup::run`
+pub(crate) fn build_router_with_hard_stop(
+    app_state: AppState,
+    hard_stop: CancellationToken,
+) -> Router {
+    let form_limits = app_state.form_limits;
+
+    // Layers run outside-in: authenticate first, so idempotency keys are
+    // scoped to the admin who sent them
+    let admin_routes = Router::new()
+        .route("/admin/dashboard", get(admin_dashboard))
+        .route(
+            "/admin/newsletters",
+            get(newsletter_form)
+                .post(publish_newsletter)
+                .layer(Extension(newsletter_form_limits(form_limits))),
+        )
+        .route("/admin/subscribers", get(list_subscribers))
+        .route(
+            "/admin/password",
+            get(change_password_form).post(change_password),
+        )
+        .route("/admin/logout", post(logout))
+        .layer(from_fn_with_state(app_state.clone(), idempotency))
+        .layer(from_fn_with_state(app_state.clone(), require_login));
+
+    // Public routes take no idempotency keys: anonymous callers cannot be
+    // told apart, so one could replay another's response
     Router::new()
         .route("/", get(|| async { "Hello, world!" }))
         .route("/healthcheck", get(healthcheck))
-        .route("/subscriptions", post(post_subscriber))
-        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
-        .layer(PropagateRequestIdLayer::x_request_id())
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+        .route("/login", get(login_form).post(login))
+        .route(
+            "/password/forgot",
+            get(forgot_password_form).post(forgot_password),
+        )
+        .route(
+            "/password/reset",
+            get(reset_password_form).post(reset_password),
+        )
+        .route(
+            "/subscriptions",
+            post(post_subscriber).layer(Extension(subscribe_form_limits(form_limits))),
+        )
+        .route("/subscriptions/confirm", get(confirm))
+        .route(
+            "/subscriptions/unsubscribe",
+            get(unsubscribe_form).post(unsubscribe),
+        )
+        .merge(admin_routes)
+        .layer(Extension(form_limits))
+        .layer(from_fn_with_state(hard_stop, cancel_on))
+        .layer(from_fn(problem_details))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(make_request_span)
                 .on_response(DefaultOnResponse::new().level(tracing::Level::INFO)),
         )
+        // Router layers wrap outwards: the id is set first, so tracing,
+        // problem details and propagation all see it
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
         .with_state(app_state)
 }
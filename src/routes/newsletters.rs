@@ -0,0 +1,131 @@
+use askama::Template;
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use hyper::StatusCode;
+use serde::Deserialize;
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::error::{ApiError, FieldError};
+use crate::routes::{AppState, accepts_html, render_html};
+use crate::session::{FlashMessage, set_flash, take_flash};
+use crate::strict_form::StrictForm;
+use crate::subscription_status::SubscriptionStatus;
+
+#[derive(Debug, Deserialize)]
+pub struct Newsletter {
+    pub title: String,
+    pub html_content: String,
+    pub text_content: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin/newsletter.html")]
+struct NewsletterTemplate {
+    flash: Option<FlashMessage>,
+}
+
+/// Composer form for a new issue
+pub async fn newsletter_form(jar: CookieJar) -> impl IntoResponse {
+    let (jar, flash) = take_flash(jar);
+    (jar, render_html(&NewsletterTemplate { flash }))
+}
+
+/// Store a new issue and queue it for every confirmed subscriber.
+/// Delivery happens in the background, see `issue_delivery_worker`.
+/// Browsers are sent back to the composer with a confirmation message.
+pub async fn publish_newsletter(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    jar: CookieJar,
+    StrictForm(newsletter): StrictForm<Newsletter>,
+) -> Result<Response, ApiError> {
+    let empty: Vec<FieldError> = [
+        ("title", &newsletter.title),
+        ("html_content", &newsletter.html_content),
+        ("text_content", &newsletter.text_content),
+    ]
+    .into_iter()
+    .filter(|(_, value)| value.trim().is_empty())
+    .map(|(field, _)| FieldError::new(field, "empty", "The field must not be empty"))
+    .collect();
+    if !empty.is_empty() {
+        return Err(
+            ApiError::bad_request("invalid-form", "The form data is invalid")
+                .with_field_errors(empty),
+        );
+    }
+
+    let mut transaction = state.db.begin().await.map_err(internal_error)?;
+
+    let newsletter_issue_id = insert_newsletter_issue(&mut transaction, &newsletter)
+        .await
+        .map_err(internal_error)?;
+
+    enqueue_delivery_tasks(&mut transaction, newsletter_issue_id)
+        .await
+        .map_err(internal_error)?;
+
+    transaction.commit().await.map_err(internal_error)?;
+
+    if accepts_html(&headers) {
+        let jar = set_flash(
+            jar,
+            FlashMessage::NewsletterPublished,
+            state.secure_cookies(),
+        );
+        Ok((jar, Redirect::to("/admin/newsletters")).into_response())
+    } else {
+        Ok(StatusCode::ACCEPTED.into_response())
+    }
+}
+
+fn internal_error(e: sqlx::Error) -> ApiError {
+    tracing::error!(error = %e, "Failed to publish newsletter");
+    ApiError::internal()
+}
+
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter: &Newsletter,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+            INSERT INTO newsletter_issues (
+                newsletter_issue_id, title, text_content, html_content, published_at
+            )
+            VALUES ($1, $2, $3, $4, now())
+        "#,
+        newsletter_issue_id,
+        newsletter.title,
+        newsletter.text_content,
+        newsletter.html_content
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(newsletter_issue_id)
+}
+
+async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_id)
+            SELECT $1, id
+            FROM subscriptions
+            WHERE status = $2
+        "#,
+        newsletter_issue_id,
+        SubscriptionStatus::Confirmed as SubscriptionStatus
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
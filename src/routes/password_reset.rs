@@ -0,0 +1,219 @@
+use askama::Template;
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use hyper::StatusCode;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::authentication::{change_password, check_password_policy, get_username};
+use crate::password_reset::{consume_reset_token, create_reset_token, peek_reset_token};
+use crate::routes::subscriptions::SubscriberEmail;
+use crate::routes::{AppState, render_html};
+use crate::secret::Secret;
+use crate::session::{FlashMessage, delete_user_sessions, set_flash, take_flash};
+use crate::strict_form::StrictForm;
+use crate::strict_query::StrictQuery;
+
+#[derive(Template)]
+#[template(path = "password_forgot.html")]
+struct ForgotPasswordTemplate {
+    flash: Option<FlashMessage>,
+}
+
+#[derive(Template)]
+#[template(path = "password_reset.html")]
+struct ResetPasswordTemplate {
+    flash: Option<FlashMessage>,
+    token: String,
+}
+
+/// Covers the email API call in the usual case; a slower call still shows
+const FORGOT_PASSWORD_MIN_DURATION: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordForm {
+    email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordParameters {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordForm {
+    token: String,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+pub async fn forgot_password_form(jar: CookieJar) -> impl IntoResponse {
+    let (jar, flash) = take_flash(jar);
+    (jar, render_html(&ForgotPasswordTemplate { flash }))
+}
+
+/// Email a reset link if the address belongs to an admin.
+/// The response is the same either way and takes at least
+/// `FORGOT_PASSWORD_MIN_DURATION`, so the endpoint can't be used to probe
+/// for accounts.
+pub async fn forgot_password(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    StrictForm(form): StrictForm<ForgotPasswordForm>,
+) -> Response {
+    let respond_at = Instant::now() + FORGOT_PASSWORD_MIN_DURATION;
+    let jar = set_flash(jar, FlashMessage::ResetEmailSent, state.secure_cookies());
+
+    let user = match sqlx::query!(
+        r#"SELECT user_id, email AS "email!" FROM users WHERE email = $1"#,
+        form.email
+    )
+    .fetch_optional(&state.db)
+    .await
+    {
+        Ok(user) => user,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to look up user by email");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // Sent inline, so a shutdown drains it like any other request
+    if let Some(user) = user
+        && let Err(e) = send_reset_email(&state, user.user_id, user.email).await
+    {
+        tracing::error!(error = %e, user_id = %user.user_id, "Failed to send password reset email");
+    }
+
+    tokio::time::sleep_until(respond_at).await;
+    (jar, Redirect::to("/login")).into_response()
+}
+
+async fn send_reset_email(
+    state: &AppState,
+    user_id: uuid::Uuid,
+    email: String,
+) -> Result<(), String> {
+    let recipient = SubscriberEmail::try_from(email).map_err(|e| e.to_string())?;
+    let token = create_reset_token(&state.db, user_id)
+        .await
+        .map_err(|e| e.to_string())?;
+    let reset_link = format!("{}/password/reset?token={token}", state.base_url);
+
+    state
+        .email
+        .send_email(
+            recipient,
+            "Reset your password".to_string(),
+            format!(
+                "Click <a href=\"{reset_link}\">here</a> to choose a new password.<br />\
+                 The link expires in one hour. If you didn't ask for it, ignore this email."
+            ),
+            format!(
+                "Visit {reset_link} to choose a new password.\n\
+                 The link expires in one hour. If you didn't ask for it, ignore this email."
+            ),
+            &[],
+        )
+        .await
+}
+
+pub async fn reset_password_form(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    StrictQuery(parameters): StrictQuery<ResetPasswordParameters>,
+) -> Response {
+    match peek_reset_token(&state.db, &parameters.token).await {
+        Ok(Some(_)) => {
+            let (jar, flash) = take_flash(jar);
+            (
+                jar,
+                render_html(&ResetPasswordTemplate {
+                    flash,
+                    token: parameters.token,
+                }),
+            )
+                .into_response()
+        }
+        Ok(None) => invalid_link(jar, &state),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to look up reset token");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Set a new password with a reset token. The token is used up and every
+/// session of the account ends.
+pub async fn reset_password(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    StrictForm(form): StrictForm<ResetPasswordForm>,
+) -> Response {
+    let secure = state.secure_cookies();
+    let retry = |jar: CookieJar, flash: FlashMessage, token: &str| {
+        let target = format!("/password/reset?token={token}");
+        (set_flash(jar, flash, secure), Redirect::to(&target)).into_response()
+    };
+
+    let user_id = match peek_reset_token(&state.db, &form.token).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return invalid_link(jar, &state),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to look up reset token");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        return retry(jar, FlashMessage::PasswordMismatch, &form.token);
+    }
+
+    let username = match get_username(&state.db, user_id).await {
+        Ok(username) => username,
+        Err(e) => {
+            tracing::error!(error = ?e, "Failed to look up username");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if let Err(violation) = check_password_policy(&username, form.new_password.expose_secret()) {
+        return retry(jar, violation.into(), &form.token);
+    }
+
+    let result: Result<bool, anyhow::Error> = async {
+        let mut transaction = state.db.begin().await?;
+        // Someone else may have redeemed the token since we peeked
+        if consume_reset_token(&mut *transaction, &form.token)
+            .await?
+            .is_none()
+        {
+            return Ok(false);
+        }
+        change_password(&mut *transaction, user_id, form.new_password).await?;
+        delete_user_sessions(&mut *transaction, user_id).await?;
+        transaction.commit().await?;
+        Ok(true)
+    }
+    .await;
+
+    match result {
+        Ok(true) => {
+            let jar = set_flash(jar, FlashMessage::PasswordChanged, secure);
+            (jar, Redirect::to("/login")).into_response()
+        }
+        Ok(false) => invalid_link(jar, &state),
+        Err(e) => {
+            tracing::error!(error = ?e, "Failed to reset password");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn invalid_link(jar: CookieJar, state: &AppState) -> Response {
+    let jar = set_flash(jar, FlashMessage::ResetLinkInvalid, state.secure_cookies());
+    (jar, Redirect::to("/password/forgot")).into_response()
+}
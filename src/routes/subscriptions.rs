@@ -1,10 +1,18 @@
-use axum::{extract::State, response::IntoResponse};
+use axum::extract::State;
 use hyper::StatusCode;
+use rand::distributions::Alphanumeric;
+use rand::{Rng, thread_rng};
 use sqlx::postgres::PgDatabaseError;
+use sqlx::{PgPool, Postgres, Transaction};
 use unicode_segmentation::UnicodeSegmentation;
+use uuid::Uuid;
 
+use crate::email_client::EmailHeader;
+use crate::error::{ApiError, FieldError, ValidationError};
 use crate::routes::AppState;
-use crate::strict_form::StrictForm;
+use crate::strict_body::StrictBody;
+use crate::subscription_status::SubscriptionStatus;
+use crate::unsubscribe_token::unsubscribe_url;
 
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
@@ -44,25 +52,34 @@ impl SubscriberName {
 }
 
 impl TryFrom<String> for SubscriberName {
-    type Error = String;
+    type Error = ValidationError;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         let length = value.chars().count();
 
         if length == 0 {
-            return Err("Name cannot be empty".into());
+            return Err(ValidationError::new("empty", "Name cannot be empty"));
         }
 
         if length > 255 {
-            return Err("Name is too long (maximum 255 characters)".into());
+            return Err(ValidationError::new(
+                "too_long",
+                "Name is too long (maximum 255 characters)",
+            ));
         }
 
         if value.contains('<') || value.contains('>') {
-            return Err("Name contains markup: potential XSS attack".into());
+            return Err(ValidationError::new(
+                "markup",
+                "Name contains markup: potential XSS attack",
+            ));
         }
 
         if value.contains(';') || value.contains("--") || value.contains("/*") {
-            return Err("Name contains forbidden characters".into());
+            return Err(ValidationError::new(
+                "forbidden_characters",
+                "Name contains forbidden characters",
+            ));
         }
 
         Ok(Self { name: value })
@@ -90,25 +107,34 @@ impl SubscriberEmail {
 }
 
 impl TryFrom<String> for SubscriberEmail {
-    type Error = String;
+    type Error = ValidationError;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        if value.len() == 0 {
-            return Err("Email cannot be empty".into());
+        if value.is_empty() {
+            return Err(ValidationError::new("empty", "Email cannot be empty"));
         }
 
         if value.len() > 255 {
-            return Err("Email is too long (maximum 255 characters)".into());
+            return Err(ValidationError::new(
+                "too_long",
+                "Email is too long (maximum 255 characters)",
+            ));
         }
 
         // Minimal but effective validation
         if !value.contains('@') {
-            return Err("Email must contain '@'".into());
+            return Err(ValidationError::new(
+                "invalid_format",
+                "Email must contain '@'",
+            ));
         }
 
         let parts: Vec<&str> = value.split('@').collect();
         if parts.len() != 2 || parts[0].is_empty() || parts[1].is_empty() {
-            return Err("Invalid email format".into());
+            return Err(ValidationError::new(
+                "invalid_format",
+                "Invalid email format",
+            ));
         }
 
         Ok(Self { email: value })
@@ -117,37 +143,167 @@ impl TryFrom<String> for SubscriberEmail {
 
 pub async fn post_subscriber(
     State(state): State<AppState>,
-    StrictForm(formdata): StrictForm<Subscriber>,
-) -> impl IntoResponse {
-    let status = match sqlx::query!(
+    StrictBody(formdata): StrictBody<Subscriber>,
+) -> Result<StatusCode, ApiError> {
+    let mut transaction = state.db.begin().await.map_err(error_for_database_error)?;
+
+    let subscriber_id = insert_subscriber(&mut transaction, &formdata)
+        .await
+        .map_err(error_for_database_error)?;
+
+    let subscription_token = generate_subscription_token();
+    store_token(&mut transaction, subscriber_id, &subscription_token)
+        .await
+        .map_err(error_for_database_error)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(error_for_database_error)?;
+
+    // Sent after committing, so no transaction stays open during the call.
+    // If it fails the subscriber is removed again, leaving the address free
+    // for a retry instead of stuck on an email that never arrived.
+    if let Err(e) =
+        send_confirmation_email(&state, subscriber_id, formdata.email, &subscription_token).await
+    {
+        tracing::error!(error = %e, "Failed to send confirmation email");
+        if let Err(e) = delete_subscriber(&state.db, subscriber_id).await {
+            tracing::error!(error = %e, "Failed to remove the unconfirmable subscriber");
+        }
+        return Err(ApiError::internal());
+    }
+
+    Ok(StatusCode::CREATED)
+}
+
+async fn insert_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber: &Subscriber,
+) -> Result<Uuid, sqlx::Error> {
+    let subscriber_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+            INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+            VALUES ($1, $2, $3, $4, $5)
+        "#,
+        subscriber_id,
+        subscriber.email.email,
+        subscriber.name.name,
+        chrono::Utc::now(),
+        SubscriptionStatus::PendingConfirmation as SubscriptionStatus
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(subscriber_id)
+}
+
+/// Remove a subscriber together with their confirmation token
+async fn delete_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
         r#"
-            INSERT INTO subscriptions (id, email, name, subscribed_at)
-            VALUES ($1, $2, $3, $4)
+            WITH tokens AS (
+                DELETE FROM subscription_tokens WHERE subscriber_id = $1
+            )
+            DELETE FROM subscriptions WHERE id = $1
         "#,
-        uuid::Uuid::new_v4(),
-        formdata.email.email,
-        formdata.name.name,
-        chrono::Utc::now()
+        subscriber_id
     )
-    .execute(&state.db)
-    .await
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn store_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    subscription_token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            INSERT INTO subscription_tokens (subscription_token, subscriber_id)
+            VALUES ($1, $2)
+        "#,
+        subscription_token,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+async fn send_confirmation_email(
+    state: &AppState,
+    subscriber_id: Uuid,
+    recipient: SubscriberEmail,
+    subscription_token: &str,
+) -> Result<(), String> {
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm?subscription_token={subscription_token}",
+        state.base_url
+    );
+    let unsubscribe_link = unsubscribe_url(
+        &state.base_url,
+        state.hmac_secret.expose_secret(),
+        subscriber_id,
+    );
+    let html_body = format!(
+        "Welcome to our newsletter!<br />\
+         Click <a href=\"{confirmation_link}\">here</a> to confirm your subscription."
+    );
+    let text_body = format!(
+        "Welcome to our newsletter!\nVisit {confirmation_link} to confirm your subscription."
+    );
+    state
+        .email
+        .send_email(
+            recipient,
+            "Welcome!".to_string(),
+            html_body,
+            text_body,
+            &EmailHeader::list_unsubscribe(&unsubscribe_link),
+        )
+        .await
+}
+
+/// Random 25-character alphanumeric token, case-sensitive
+fn generate_subscription_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(25)
+        .collect()
+}
+
+fn error_for_database_error(error: sqlx::Error) -> ApiError {
+    if let sqlx::Error::Database(db_err) = &error
+        && let Some(pg_err) = db_err.try_downcast_ref::<PgDatabaseError>()
     {
-        Ok(_) => StatusCode::CREATED,
-
-        Err(sqlx::Error::Database(db_err)) => {
-            if let Some(pg_err) = db_err.try_downcast_ref::<PgDatabaseError>() {
-                match pg_err.code() {
-                    "23505" => StatusCode::CONFLICT,    // unique violation
-                    "23503" => StatusCode::BAD_REQUEST, // FK violation
-                    _ => StatusCode::INTERNAL_SERVER_ERROR,
-                }
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
+        match pg_err.code() {
+            // unique violation
+            "23505" => {
+                return ApiError::new(
+                    StatusCode::CONFLICT,
+                    "already-subscribed",
+                    "This email address is already subscribed",
+                )
+                .with_field_errors(vec![FieldError::new(
+                    "email",
+                    "taken",
+                    "already subscribed",
+                )]);
             }
+            // FK violation
+            "23503" => {
+                return ApiError::bad_request(
+                    "invalid-reference",
+                    "The request refers to a record that does not exist",
+                );
+            }
+            _ => {}
         }
+    }
 
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
-    };
-
-    (status, "".to_string())
+    tracing::error!(error = %error, "Database error while subscribing");
+    ApiError::internal()
 }
@@ -0,0 +1,57 @@
+use axum::extract::State;
+use hyper::StatusCode;
+use serde::Deserialize;
+
+use crate::error::ApiError;
+use crate::routes::AppState;
+use crate::strict_query::StrictQuery;
+use crate::subscription_status::{SubscriptionStatus, transition_subscriber};
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmParameters {
+    subscription_token: String,
+}
+
+/// Flip a pending subscriber to confirmed using the token from their welcome email
+pub async fn confirm(
+    State(state): State<AppState>,
+    StrictQuery(parameters): StrictQuery<ConfirmParameters>,
+) -> Result<StatusCode, ApiError> {
+    let subscriber_id = sqlx::query!(
+        r#"
+            SELECT subscriber_id FROM subscription_tokens
+            WHERE subscription_token = $1
+        "#,
+        parameters.subscription_token
+    )
+    .fetch_optional(&state.db)
+    .await
+    .map_err(internal_error)?
+    .ok_or_else(|| {
+        ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid-token",
+            "The confirmation link is invalid",
+        )
+    })?
+    .subscriber_id;
+
+    let mut transaction = state.db.begin().await.map_err(internal_error)?;
+
+    // An old confirmation link clicked after unsubscribing is an invalid
+    // transition, reported as a conflict
+    transition_subscriber(
+        &mut transaction,
+        subscriber_id,
+        SubscriptionStatus::Confirmed,
+    )
+    .await?;
+
+    transaction.commit().await.map_err(internal_error)?;
+    Ok(StatusCode::OK)
+}
+
+fn internal_error(e: sqlx::Error) -> ApiError {
+    tracing::error!(error = %e, "Failed to confirm subscriber");
+    ApiError::internal()
+}
@@ -0,0 +1,82 @@
+use axum::{extract::State, response::Html};
+use hyper::StatusCode;
+use serde::Deserialize;
+
+use crate::error::ApiError;
+use crate::routes::AppState;
+use crate::strict_query::StrictQuery;
+use crate::subscription_status::{SubscriptionStatus, TransitionError, transition_subscriber};
+use crate::unsubscribe_token::verify_unsubscribe_token;
+
+#[derive(Debug, Deserialize)]
+pub struct UnsubscribeParameters {
+    token: String,
+}
+
+/// Landing page for the link in the email footer.
+/// Link scanners follow GET links, so this only asks for confirmation;
+/// the actual unsubscribe happens on POST.
+pub async fn unsubscribe_form(
+    State(state): State<AppState>,
+    StrictQuery(parameters): StrictQuery<UnsubscribeParameters>,
+) -> Result<Html<String>, ApiError> {
+    if verify_unsubscribe_token(state.hmac_secret.expose_secret(), &parameters.token).is_none() {
+        return Err(invalid_token());
+    }
+
+    Ok(Html(format!(
+        r#"<!doctype html>
+<html lang="en">
+  <head><meta charset="utf-8"><title>Unsubscribe</title></head>
+  <body>
+    <form method="post" action="/subscriptions/unsubscribe?token={}">
+      <p>Do you want to stop receiving our newsletter?</p>
+      <button type="submit">Unsubscribe</button>
+    </form>
+  </body>
+</html>"#,
+        parameters.token
+    )))
+}
+
+/// RFC 8058 one-click endpoint. Mailbox providers POST
+/// `List-Unsubscribe=One-Click` here; the token in the query is all we need.
+pub async fn unsubscribe(
+    State(state): State<AppState>,
+    StrictQuery(parameters): StrictQuery<UnsubscribeParameters>,
+) -> Result<Html<&'static str>, ApiError> {
+    let subscriber_id =
+        verify_unsubscribe_token(state.hmac_secret.expose_secret(), &parameters.token)
+            .ok_or_else(invalid_token)?;
+
+    let mut transaction = state.db.begin().await.map_err(internal_error)?;
+
+    match transition_subscriber(
+        &mut transaction,
+        subscriber_id,
+        SubscriptionStatus::Unsubscribed,
+    )
+    .await
+    {
+        // Bounced and complained addresses are already off the list
+        Ok(_) | Err(TransitionError::Invalid(_)) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    transaction.commit().await.map_err(internal_error)?;
+
+    Ok(Html("<p>You have been unsubscribed.</p>"))
+}
+
+fn invalid_token() -> ApiError {
+    ApiError::new(
+        StatusCode::UNAUTHORIZED,
+        "invalid-token",
+        "The unsubscribe link is invalid",
+    )
+}
+
+fn internal_error(e: sqlx::Error) -> ApiError {
+    tracing::error!(error = %e, "Failed to unsubscribe");
+    ApiError::internal()
+}
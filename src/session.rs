@@ -0,0 +1,262 @@
+//! src/session.rs
+//! Cookie sessions for the admin area, stored server-side in Postgres.
+//!
+//! The cookie only carries a random session id. A session ends when it
+//! is idle for longer than `IDLE_TIMEOUT` or older than `ABSOLUTE_TIMEOUT`,
+//! whichever comes first. Logging in always issues a fresh id. Expired
+//! rows are swept every `EXPIRY_INTERVAL`.
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rand::{RngCore, thread_rng};
+use sha2::{Digest, Sha256};
+use sqlx::{PgExecutor, PgPool};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+pub const SESSION_COOKIE: &str = "session_id";
+pub const FLASH_COOKIE: &str = "_flash";
+
+/// Sessions end after this much inactivity
+pub const IDLE_TIMEOUT_SECS: f64 = 30.0 * 60.0;
+/// ...and after this long no matter what
+pub const ABSOLUTE_TIMEOUT_SECS: f64 = 12.0 * 60.0 * 60.0;
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Start a session for `user_id` and return the cookie value
+pub async fn create_session(pool: &PgPool, user_id: Uuid) -> Result<String, sqlx::Error> {
+    let mut bytes = [0u8; 32];
+    thread_rng().fill_bytes(&mut bytes);
+    let session_id = URL_SAFE_NO_PAD.encode(bytes);
+
+    sqlx::query!(
+        r#"
+            INSERT INTO sessions (session_id_hash, user_id, created_at, last_seen_at)
+            VALUES ($1, $2, now(), now())
+        "#,
+        hash_session_id(&session_id),
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(session_id)
+}
+
+/// Resolve a session cookie to its user, refreshing the idle timer.
+/// Expired sessions are removed on the way.
+pub async fn get_session_user(
+    pool: &PgPool,
+    session_id: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let session_id_hash = hash_session_id(session_id);
+    let user_id = sqlx::query!(
+        r#"
+            UPDATE sessions
+            SET last_seen_at = now()
+            WHERE session_id_hash = $1
+              AND last_seen_at > now() - make_interval(secs => $2)
+              AND created_at > now() - make_interval(secs => $3)
+            RETURNING user_id
+        "#,
+        session_id_hash,
+        IDLE_TIMEOUT_SECS,
+        ABSOLUTE_TIMEOUT_SECS
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|row| row.user_id);
+
+    if user_id.is_none() {
+        sqlx::query!(
+            r#"DELETE FROM sessions WHERE session_id_hash = $1"#,
+            session_id_hash
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(user_id)
+}
+
+/// End every session of a user, e.g. after a password change
+pub async fn delete_user_sessions(
+    executor: impl PgExecutor<'_>,
+    user_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"DELETE FROM sessions WHERE user_id = $1"#, user_id)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_session(pool: &PgPool, session_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM sessions WHERE session_id_hash = $1"#,
+        hash_session_id(session_id)
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Delete sessions past either timeout, returning how many went
+pub async fn expire_sessions(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let deleted = sqlx::query!(
+        r#"
+            DELETE FROM sessions
+            WHERE last_seen_at <= now() - make_interval(secs => $1)
+               OR created_at <= now() - make_interval(secs => $2)
+        "#,
+        IDLE_TIMEOUT_SECS,
+        ABSOLUTE_TIMEOUT_SECS
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(deleted)
+}
+
+/// Sweep expired sessions every `EXPIRY_INTERVAL` until `shutdown` is cancelled
+pub async fn run_expiry_until_stopped(pool: PgPool, shutdown: CancellationToken) {
+    loop {
+        tokio::select! {
+            () = tokio::time::sleep(EXPIRY_INTERVAL) => {}
+            () = shutdown.cancelled() => return,
+        }
+        match expire_sessions(&pool).await {
+            Ok(deleted) => tracing::debug!(deleted, "Expired sessions"),
+            Err(e) => tracing::warn!(error = %e, "Failed to expire sessions"),
+        }
+    }
+}
+
+fn hash_session_id(session_id: &str) -> String {
+    format!("{:x}", Sha256::digest(session_id.as_bytes()))
+}
+
+/// Secure, HttpOnly, SameSite cookie carrying the session id.
+/// `secure` is off only for plain-http local setups.
+pub fn session_cookie(session_id: String, secure: bool) -> Cookie<'static> {
+    Cookie::build((SESSION_COOKIE, session_id))
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax)
+        .build()
+}
+
+pub fn session_id(jar: &CookieJar) -> Option<String> {
+    jar.get(SESSION_COOKIE).map(|c| c.value().to_string())
+}
+
+pub fn remove_session_cookie(jar: CookieJar) -> CookieJar {
+    jar.remove(Cookie::build(SESSION_COOKIE).path("/"))
+}
+
+/// One-shot messages shown on the next page the browser loads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashMessage {
+    LoginFailed,
+    LoggedOut,
+    LoginRequired,
+    NewsletterPublished,
+    PasswordChanged,
+    PasswordMismatch,
+    CurrentPasswordWrong,
+    PasswordTooShort,
+    PasswordTooLong,
+    PasswordContainsUsername,
+    PasswordUnchanged,
+    ResetEmailSent,
+    ResetLinkInvalid,
+}
+
+impl FlashMessage {
+    const ALL: [FlashMessage; 13] = [
+        FlashMessage::LoginFailed,
+        FlashMessage::LoggedOut,
+        FlashMessage::LoginRequired,
+        FlashMessage::NewsletterPublished,
+        FlashMessage::PasswordChanged,
+        FlashMessage::PasswordMismatch,
+        FlashMessage::CurrentPasswordWrong,
+        FlashMessage::PasswordTooShort,
+        FlashMessage::PasswordTooLong,
+        FlashMessage::PasswordContainsUsername,
+        FlashMessage::PasswordUnchanged,
+        FlashMessage::ResetEmailSent,
+        FlashMessage::ResetLinkInvalid,
+    ];
+
+    fn code(&self) -> &'static str {
+        match self {
+            FlashMessage::LoginFailed => "login_failed",
+            FlashMessage::LoggedOut => "logged_out",
+            FlashMessage::LoginRequired => "login_required",
+            FlashMessage::NewsletterPublished => "newsletter_published",
+            FlashMessage::PasswordChanged => "password_changed",
+            FlashMessage::PasswordMismatch => "password_mismatch",
+            FlashMessage::CurrentPasswordWrong => "current_password_wrong",
+            FlashMessage::PasswordTooShort => "password_too_short",
+            FlashMessage::PasswordTooLong => "password_too_long",
+            FlashMessage::PasswordContainsUsername => "password_contains_username",
+            FlashMessage::PasswordUnchanged => "password_unchanged",
+            FlashMessage::ResetEmailSent => "reset_email_sent",
+            FlashMessage::ResetLinkInvalid => "reset_link_invalid",
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|flash| flash.code() == code)
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            FlashMessage::LoginFailed => "Authentication failed.",
+            FlashMessage::LoggedOut => "You have successfully logged out.",
+            FlashMessage::LoginRequired => "Please log in to continue.",
+            FlashMessage::NewsletterPublished => {
+                "The newsletter issue has been published and is being delivered."
+            }
+            FlashMessage::PasswordChanged => "Your password has been changed. Please log in again.",
+            FlashMessage::PasswordMismatch => "The two new passwords don't match.",
+            FlashMessage::CurrentPasswordWrong => "The current password is incorrect.",
+            FlashMessage::PasswordTooShort => {
+                "The new password must be at least 12 characters long."
+            }
+            FlashMessage::PasswordTooLong => {
+                "The new password must be at most 128 characters long."
+            }
+            FlashMessage::PasswordContainsUsername => {
+                "The new password must not contain your username."
+            }
+            FlashMessage::PasswordUnchanged => {
+                "The new password must be different from the current one."
+            }
+            FlashMessage::ResetEmailSent => {
+                "If that address belongs to an account, a password reset link is on its way."
+            }
+            FlashMessage::ResetLinkInvalid => "That password reset link is invalid or has expired.",
+        }
+    }
+}
+
+/// Queue a flash message for the next request
+pub fn set_flash(jar: CookieJar, flash: FlashMessage, secure: bool) -> CookieJar {
+    jar.add(
+        Cookie::build((FLASH_COOKIE, flash.code()))
+            .path("/")
+            .http_only(true)
+            .secure(secure)
+            .same_site(SameSite::Lax)
+            .build(),
+    )
+}
+
+/// Read and clear the pending flash message
+pub fn take_flash(jar: CookieJar) -> (CookieJar, Option<FlashMessage>) {
+    let flash = jar
+        .get(FLASH_COOKIE)
+        .and_then(|c| FlashMessage::from_code(c.value()));
+    (jar.remove(Cookie::build(FLASH_COOKIE).path("/")), flash)
+}
@@ -1,36 +1,222 @@
-//use axum::extract::connect_info::IntoMakeServiceWithConnectInfo;
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rand::Rng;
 use sqlx::PgPool;
+use sqlx::migrate::Migrator;
 use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
-use crate::email_client::EmailClient;
+use crate::configuration::ConnectRetrySettings;
+use crate::error::ApiError;
+use crate::issue_delivery_worker::run_worker_until_stopped;
 use crate::routes::AppState;
-use crate::routes::build_router;
+use crate::routes::build_router_with_hard_stop;
+use crate::{idempotency, password_reset, session};
 
-/// Run the Axum app on the given address
-/// If `bind_addr` is `None`, it binds to a random local port
+/// The app's migrations, run at startup and checked by `/health/ready`
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// How long cancelled requests get to send their 503 before `run` moves on
+const HARD_STOP_GRACE: Duration = Duration::from_secs(1);
+
+/// Serve the app on `listener` until `shutdown` is cancelled.
+///
+/// Shutting down stops accepting connections, then gives in-flight requests
+/// and the delivery worker's current task `drain_timeout` to finish before
+/// the pool is closed. Requests still running after that are answered with
+/// a 503 and the worker is aborted.
 pub async fn run(
-    bind_addr: Option<SocketAddr>,
-    connection_pool: PgPool,
-    email_service: EmailClient,
+    listener: TcpListener,
+    app_state: AppState,
+    shutdown: CancellationToken,
+    drain_timeout: Duration,
 ) -> std::io::Result<()> {
-    let app_state = AppState {
-        db: connection_pool,
-        email: email_service,
-    };
-    let app = build_router(app_state);
+    let pool = app_state.db.clone();
+
+    // Newsletter deliveries run next to the HTTP server
+    let mut worker = tokio::spawn(run_worker_until_stopped(
+        app_state.clone(),
+        shutdown.clone(),
+    ));
 
-    // Bind listener
-    let addr = bind_addr.unwrap_or(([127, 0, 0, 1], 0).into());
-    let listener = TcpListener::bind(addr).await?;
-    let local_addr = listener.local_addr()?;
+    // Old idempotency keys, sessions and reset tokens are swept alongside
+    let expiry = [
+        tokio::spawn(idempotency::run_expiry_until_stopped(
+            pool.clone(),
+            shutdown.clone(),
+        )),
+        tokio::spawn(session::run_expiry_until_stopped(
+            pool.clone(),
+            shutdown.clone(),
+        )),
+        tokio::spawn(password_reset::run_expiry_until_stopped(
+            pool.clone(),
+            shutdown.clone(),
+        )),
+    ];
 
-    println!("Listening on http://{local_addr}");
+    // Fires at the drain deadline, cancelling whatever is still running
+    let hard_stop = CancellationToken::new();
+    let app = build_router_with_hard_stop(app_state, hard_stop.clone());
+    println!("Listening on http://{}", listener.local_addr()?);
 
-    // Run the server
-    axum::serve(
+    let server = axum::serve(
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
-    .await
+    .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+    .into_future();
+    let mut server = std::pin::pin!(server);
+
+    let served = tokio::select! {
+        result = &mut server => Some(result),
+        () = shutdown.cancelled() => None,
+    };
+    // One deadline for requests and worker alike, counted from the moment
+    // shutdown began
+    let deadline = Instant::now() + drain_timeout;
+    match served {
+        Some(result) => result?,
+        None => {
+            tracing::info!(?drain_timeout, "Shutting down, draining in-flight requests");
+            match tokio::time::timeout_at(deadline, &mut server).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    tracing::warn!("Drain deadline passed, cancelling in-flight requests");
+                    hard_stop.cancel();
+                    // Cancelled requests answer at once, so this is only a backstop
+                    if tokio::time::timeout(HARD_STOP_GRACE, &mut server)
+                        .await
+                        .is_err()
+                    {
+                        tracing::warn!("Connections still open after cancelling requests");
+                    }
+                }
+            }
+        }
+    }
+
+    if tokio::time::timeout_at(deadline, &mut worker)
+        .await
+        .is_err()
+    {
+        tracing::warn!("Delivery worker did not finish its task before the deadline");
+        worker.abort();
+    }
+    // A sweep cut short is rolled back and simply redone next time
+    for sweep in &expiry {
+        sweep.abort();
+    }
+    pool.close().await;
+    tracing::info!("Shutdown complete");
+    Ok(())
+}
+
+/// Answer with a 503 instead of finishing the request once `hard_stop` fires.
+/// Dropping the handler rolls back any transaction it had open.
+pub(crate) async fn cancel_on(
+    State(hard_stop): State<CancellationToken>,
+    request: Request,
+    next: Next,
+) -> Response {
+    tokio::select! {
+        response = next.run(request) => response,
+        () = hard_stop.cancelled() => ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "shutting-down",
+            "The server shut down before the request completed",
+        )
+        .into_response(),
+    }
+}
+
+/// Resolves on SIGINT (Ctrl+C) or SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+/// Delays between connection attempts: doubling from `initial` up to `max`,
+/// each one picked at random from its upper half so that restarted
+/// replicas don't retry in lockstep
+pub struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { next: initial, max }
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        let half = delay / 2;
+        Some(half + rand::thread_rng().gen_range(Duration::ZERO..=half))
+    }
+}
+
+/// Retry a trivial query until the database answers. Gives up with the last
+/// error once `max_wait` has passed, or keeps going forever without one.
+pub async fn wait_for_database(
+    pool: &PgPool,
+    retry: &ConnectRetrySettings,
+    max_wait: Option<Duration>,
+) -> Result<(), sqlx::Error> {
+    let deadline = max_wait.map(|max_wait| Instant::now() + max_wait);
+    let mut backoff = Backoff::new(retry.initial_backoff(), retry.max_backoff());
+    let mut attempt: u32 = 0;
+    loop {
+        attempt += 1;
+        let error = match sqlx::query("SELECT 1").execute(pool).await {
+            Ok(_) => {
+                tracing::info!(attempt, "Connected to the database");
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+
+        let delay = backoff.next().expect("backoff never ends");
+        if deadline.is_some_and(|deadline| Instant::now() + delay > deadline) {
+            tracing::error!(attempt, error = %error, "Giving up on connecting to the database");
+            return Err(error);
+        }
+        tracing::warn!(
+            attempt,
+            error = %error,
+            retry_in_ms = delay.as_millis() as u64,
+            "Database not reachable yet, retrying"
+        );
+        tokio::time::sleep(delay).await;
+    }
 }
@@ -0,0 +1,80 @@
+// strict_body.rs
+// StrictBody extractor: picks StrictForm or StrictJson based on Content-Type
+// - application/x-www-form-urlencoded goes through StrictForm, which also checks the charset
+// - application/json goes through StrictJson
+// - anything else, or no Content-Type at all, is rejected with 415
+
+use axum::{
+    body::Body,
+    extract::FromRequest,
+    http::{Request, StatusCode},
+    response::IntoResponse,
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::ApiError;
+use crate::strict_form::{FORM_URLENCODED, StrictForm, StrictFormRejection, parse_content_type};
+use crate::strict_json::{StrictJson, StrictJsonRejection};
+
+/// StrictBody wrapper — use in handlers as `StrictBody<T>` to accept
+/// either a form or a JSON document with the same shape
+pub struct StrictBody<T>(pub T);
+
+#[derive(Debug)]
+pub enum StrictBodyRejection {
+    UnsupportedMediaType,
+    Form(StrictFormRejection),
+    Json(StrictJsonRejection),
+}
+
+impl IntoResponse for StrictBodyRejection {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            StrictBodyRejection::UnsupportedMediaType => ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported-media-type",
+                "The request body has an unsupported media type",
+            )
+            .with_detail("Expected application/x-www-form-urlencoded or application/json")
+            .into_response(),
+            StrictBodyRejection::Form(rejection) => rejection.into_response(),
+            StrictBodyRejection::Json(rejection) => rejection.into_response(),
+        }
+    }
+}
+
+enum BodyKind {
+    Form,
+    Json,
+}
+
+// Only the essence matters here; StrictForm checks the charset itself
+fn body_kind(req: &Request<Body>) -> Option<BodyKind> {
+    match parse_content_type(req.headers())?.essence.as_str() {
+        FORM_URLENCODED => Some(BodyKind::Form),
+        "application/json" => Some(BodyKind::Json),
+        _ => None,
+    }
+}
+
+impl<S, T> FromRequest<S, Body> for StrictBody<T>
+where
+    T: DeserializeOwned + Send + 'static,
+    S: Send + Sync,
+{
+    type Rejection = StrictBodyRejection;
+
+    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        match body_kind(&req) {
+            Some(BodyKind::Form) => StrictForm::from_request(req, state)
+                .await
+                .map(|StrictForm(t)| StrictBody(t))
+                .map_err(StrictBodyRejection::Form),
+            Some(BodyKind::Json) => StrictJson::from_request(req, state)
+                .await
+                .map(|StrictJson(t)| StrictBody(t))
+                .map_err(StrictBodyRejection::Json),
+            None => Err(StrictBodyRejection::UnsupportedMediaType),
+        }
+    }
+}
@@ -1,54 +1,170 @@
 // strict_form.rs
-// Production-ready StrictForm extractor for Axum using serde_urlencoded
-// - rejects invalid percent-encoding
-// - rejects invalid (non-UTF-8) decoded fields
-// - enforces configurable body size limit and field limits
-// - deserializes into T using serde_urlencoded after strict validation
+// Production-ready StrictForm extractor for Axum
+// - requires Content-Type application/x-www-form-urlencoded, UTF-8 only
+// - enforces per-route limits on body size, field count, key and value length
+// - validates and deserializes the body with the shared rules in urlencoded.rs
 
 use axum::{
     body::{Body, Bytes, to_bytes},
     extract::FromRequest,
-    http::Request,
+    http::{HeaderMap, Request, StatusCode, header::CONTENT_TYPE},
     response::IntoResponse,
 };
+use http_body_util::LengthLimitError;
 use serde::de::DeserializeOwned;
-use serde_urlencoded;
-use std::collections::HashMap;
 
-/// Configuration constants — adjust to your needs
-const MAX_BODY_BYTES: usize = 64 * 1024; // 16 KiB
-const MAX_FIELDS: usize = 256; // maximum number of form pairs
+use crate::error::ApiError;
+use crate::urlencoded::{UrlencodedError, UrlencodedSource, from_bytes};
+
+/// Limits applied by `StrictForm` and `StrictQuery`.
+///
+/// The extractors read them from the request extensions, so a route can set
+/// its own with `.layer(Extension(limits))`. `build_router` installs the
+/// defaults from `Settings` for every other route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StrictFormLimits {
+    /// Size of the raw, still percent-encoded body or query string
+    pub max_body_bytes: usize,
+    /// Number of key/value pairs
+    pub max_fields: usize,
+    /// Length of a decoded key in bytes
+    pub max_key_len: usize,
+    /// Length of a decoded value in bytes
+    pub max_value_len: usize,
+}
+
+impl Default for StrictFormLimits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 64 * 1024, // 64 KiB
+            max_fields: 256,
+            max_key_len: 128,
+            max_value_len: 64 * 1024,
+        }
+    }
+}
+
+pub const FORM_URLENCODED: &str = "application/x-www-form-urlencoded";
+
+/// Why the Content-Type of a form was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MediaTypeError {
+    Missing,
+    Unexpected(String),
+    Charset(String),
+}
+
+impl std::fmt::Display for MediaTypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaTypeError::Missing => write!(f, "Expected Content-Type {FORM_URLENCODED}"),
+            MediaTypeError::Unexpected(media_type) => {
+                write!(f, "Expected {FORM_URLENCODED}, got {media_type}")
+            }
+            MediaTypeError::Charset(charset) => {
+                write!(f, "Only charset=utf-8 is accepted, got charset={charset}")
+            }
+        }
+    }
+}
+
+/// A Content-Type split into its lowercased essence and its charset
+/// parameter, if any. `None` if the header is not valid.
+pub(crate) struct ContentType {
+    pub essence: String,
+    pub charset: Option<String>,
+}
+
+pub(crate) fn parse_content_type(headers: &HeaderMap) -> Option<ContentType> {
+    let value = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let mut parts = value.split(';');
+    let essence = parts.next()?.trim().to_ascii_lowercase();
+    if essence.is_empty() {
+        return None;
+    }
+    let mut charset = None;
+    for param in parts {
+        let param = param.trim();
+        if param.is_empty() {
+            continue;
+        }
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("charset") {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            charset = Some(value.to_string());
+        }
+    }
+    Some(ContentType { essence, charset })
+}
+
+/// Accept `application/x-www-form-urlencoded` with no charset or UTF-8.
+/// Any other declared charset is refused rather than silently read as UTF-8.
+pub fn check_content_type(headers: &HeaderMap) -> Result<(), MediaTypeError> {
+    let Some(content_type) = parse_content_type(headers) else {
+        return match headers.get(CONTENT_TYPE) {
+            None => Err(MediaTypeError::Missing),
+            Some(value) => Err(MediaTypeError::Unexpected(
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )),
+        };
+    };
+    if content_type.essence != FORM_URLENCODED {
+        return Err(MediaTypeError::Unexpected(content_type.essence));
+    }
+    match content_type.charset {
+        Some(charset) if !charset.eq_ignore_ascii_case("utf-8") => {
+            Err(MediaTypeError::Charset(charset))
+        }
+        _ => Ok(()),
+    }
+}
 
 /// StrictForm wrapper — use in handlers as `StrictForm<T>`
 pub struct StrictForm<T>(pub T);
 
 #[derive(Debug)]
 pub enum StrictFormRejection {
+    UnsupportedMediaType(MediaTypeError),
     ReadBody,
     PayloadTooLarge,
-    InvalidPercentEncoding,
-    InvalidUtf8,
-    TooManyFields,
-    InvalidFormStructure(String),
+    Invalid(UrlencodedError),
 }
 
-impl IntoResponse for StrictFormRejection {
-    fn into_response(self) -> axum::response::Response {
-        let code = match &self {
-            StrictFormRejection::ReadBody => axum::http::StatusCode::BAD_REQUEST,
-            StrictFormRejection::PayloadTooLarge => axum::http::StatusCode::PAYLOAD_TOO_LARGE,
-            StrictFormRejection::InvalidPercentEncoding => axum::http::StatusCode::BAD_REQUEST,
-            StrictFormRejection::InvalidUtf8 => axum::http::StatusCode::UNPROCESSABLE_ENTITY,
-            StrictFormRejection::TooManyFields => axum::http::StatusCode::BAD_REQUEST,
-            StrictFormRejection::InvalidFormStructure(_) => axum::http::StatusCode::BAD_REQUEST,
-        };
+impl From<UrlencodedError> for StrictFormRejection {
+    fn from(error: UrlencodedError) -> Self {
+        StrictFormRejection::Invalid(error)
+    }
+}
 
-        let body = match self {
-            StrictFormRejection::InvalidFormStructure(s) => s,
-            other => format!("{:?}", other),
-        };
+impl From<StrictFormRejection> for ApiError {
+    fn from(rejection: StrictFormRejection) -> Self {
+        match rejection {
+            StrictFormRejection::UnsupportedMediaType(error) => ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported-media-type",
+                "The request body has an unsupported media type",
+            )
+            .with_detail(error.to_string()),
+            StrictFormRejection::ReadBody => {
+                ApiError::bad_request("unreadable-body", "The request body could not be read")
+            }
+            StrictFormRejection::PayloadTooLarge => ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload-too-large",
+                "The request body is too large",
+            ),
+            StrictFormRejection::Invalid(error) => error.into_api_error(UrlencodedSource::Form),
+        }
+    }
+}
 
-        (code, body).into_response()
+impl IntoResponse for StrictFormRejection {
+    fn into_response(self) -> axum::response::Response {
+        ApiError::from(self).into_response()
     }
 }
 
@@ -66,131 +182,25 @@ where
         Output = Result<Self, <Self as FromRequest<(), axum::body::Body>>::Rejection>,
     > + Send {
         Box::pin(async move {
-            let whole: Bytes = to_bytes(req.into_body(), MAX_BODY_BYTES)
+            let limits = req
+                .extensions()
+                .get::<StrictFormLimits>()
+                .copied()
+                .unwrap_or_default();
+            check_content_type(req.headers()).map_err(StrictFormRejection::UnsupportedMediaType)?;
+
+            let whole: Bytes = to_bytes(req.into_body(), limits.max_body_bytes)
                 .await
-                .map_err(|_| StrictFormRejection::ReadBody)?;
-            let whole = whole.to_vec();
-
-            if percent_encoding_is_invalid(&whole) {
-                return Err(StrictFormRejection::InvalidPercentEncoding);
-            }
-
-            let parsed = parse_raw_form(&whole);
-            if parsed.len() > MAX_FIELDS {
-                return Err(StrictFormRejection::TooManyFields);
-            }
-
-            // convert raw bytes to UTF-8 strings
-            let mut form_map: HashMap<String, String> = HashMap::new();
-            for (raw_k, raw_v) in parsed.into_iter() {
-                // Reject NUL bytes in keys or values
-                if raw_k.contains(&0) || raw_v.contains(&0) {
-                    return Err(StrictFormRejection::InvalidUtf8);
-                }
-
-                let k = String::from_utf8(raw_k).map_err(|_| StrictFormRejection::InvalidUtf8)?;
-                let v = String::from_utf8(raw_v).map_err(|_| StrictFormRejection::InvalidUtf8)?;
-                form_map.insert(k, v);
-            }
-
-            // deserialize into T using serde_urlencoded
-            let t: T = serde_urlencoded::from_str(
-                &serde_urlencoded::to_string(&form_map)
-                    .map_err(|e| StrictFormRejection::InvalidFormStructure(e.to_string()))?,
-            )
-            .map_err(|e| StrictFormRejection::InvalidFormStructure(e.to_string()))?;
+                .map_err(|e| {
+                    if e.into_inner().is::<LengthLimitError>() {
+                        StrictFormRejection::PayloadTooLarge
+                    } else {
+                        StrictFormRejection::ReadBody
+                    }
+                })?;
+            let t: T = from_bytes(&whole, &limits)?;
 
             Ok(StrictForm(t))
         })
     }
 }
-
-fn percent_encoding_is_invalid(bytes: &[u8]) -> bool {
-    let mut i = 0usize;
-    while i < bytes.len() {
-        match bytes[i] {
-            b'%' => {
-                if i + 2 >= bytes.len() {
-                    return true;
-                }
-                if !is_hex_digit(bytes[i + 1]) || !is_hex_digit(bytes[i + 2]) {
-                    return true;
-                }
-                i += 3;
-            }
-            _ => i += 1,
-        }
-    }
-    false
-}
-
-#[inline]
-fn is_hex_digit(b: u8) -> bool {
-    (b'0'..=b'9').contains(&b) || (b'a'..=b'f').contains(&b) || (b'A'..=b'F').contains(&b)
-}
-
-fn parse_raw_form(data: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
-    let mut out = Vec::new();
-    for pair in data.split(|&b| b == b'&') {
-        if pair.is_empty() {
-            continue;
-        }
-        let mut eq_idx = None;
-        for (i, &b) in pair.iter().enumerate() {
-            if b == b'=' {
-                eq_idx = Some(i);
-                break;
-            }
-        }
-        if let Some(idx) = eq_idx {
-            let (k, v) = pair.split_at(idx);
-            let v = &v[1..];
-            let key = percent_decode_bytes(k);
-            let val = percent_decode_bytes(v);
-            out.push((key, val));
-        } else {
-            let key = percent_decode_bytes(pair);
-            out.push((key, Vec::new()));
-        }
-    }
-    out
-}
-
-fn percent_decode_bytes(input: &[u8]) -> Vec<u8> {
-    let mut out = Vec::with_capacity(input.len());
-    let mut i = 0;
-    while i < input.len() {
-        match input[i] {
-            b'+' => {
-                out.push(b' ');
-                i += 1;
-            }
-            b'%' => {
-                if i + 2 < input.len() {
-                    if let (Some(h), Some(l)) = (from_hex(input[i + 1]), from_hex(input[i + 2])) {
-                        out.push(h * 16 + l);
-                        i += 3;
-                        continue;
-                    }
-                }
-                out.push(b'%');
-                i += 1;
-            }
-            b => {
-                out.push(b);
-                i += 1;
-            }
-        }
-    }
-    out
-}
-
-#[inline]
-fn from_hex(b: u8) -> Option<u8> {
-    match b {
-        b'0'..=b'9' => Some(b - b'0'),
-        b'a'..=b'f' => Some(b - b'a' + 10),
-        b'A'..=b'F' => Some(b - b'A' + 10),
-        _ => None,
-    }
-}
@@ -0,0 +1,219 @@
+// strict_json.rs
+// StrictJson extractor for Axum, the JSON counterpart of StrictForm
+// - rejects bodies that are not valid UTF-8
+// - rejects NUL characters anywhere in keys or string values, including \u0000 escapes
+// - enforces the same per-route body size limit as StrictForm
+// - rejects fields that T does not know about, and keys given more than once
+
+use axum::{
+    body::{Body, Bytes, to_bytes},
+    extract::FromRequest,
+    http::{Request, StatusCode},
+    response::IntoResponse,
+};
+use http_body_util::LengthLimitError;
+use serde::de::{self, DeserializeOwned, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Number, Value};
+use std::fmt;
+
+use crate::error::{ApiError, FieldError, field_errors_from_serde};
+use crate::strict_form::StrictFormLimits;
+
+/// StrictJson wrapper — use in handlers as `StrictJson<T>`
+pub struct StrictJson<T>(pub T);
+
+#[derive(Debug)]
+pub enum StrictJsonRejection {
+    ReadBody,
+    PayloadTooLarge,
+    InvalidUtf8,
+    NulCharacter,
+    MalformedJson(String),
+    UnknownFields(Vec<String>),
+    InvalidJsonStructure(String),
+}
+
+impl From<StrictJsonRejection> for ApiError {
+    fn from(rejection: StrictJsonRejection) -> Self {
+        match rejection {
+            StrictJsonRejection::ReadBody => {
+                ApiError::bad_request("unreadable-body", "The request body could not be read")
+            }
+            StrictJsonRejection::PayloadTooLarge => ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload-too-large",
+                "The request body is too large",
+            ),
+            StrictJsonRejection::InvalidUtf8 => ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid-utf8",
+                "The request body is not valid UTF-8",
+            ),
+            StrictJsonRejection::NulCharacter => ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "nul-character",
+                "The document contains a NUL character",
+            ),
+            StrictJsonRejection::MalformedJson(message) => {
+                ApiError::bad_request("malformed-json", "The request body is not valid JSON")
+                    .with_detail(message)
+            }
+            StrictJsonRejection::UnknownFields(fields) => {
+                ApiError::bad_request("unknown-fields", "The document has unexpected fields")
+                    .with_field_errors(
+                        fields
+                            .into_iter()
+                            .map(|field| FieldError::new(field, "unknown", "unknown field"))
+                            .collect(),
+                    )
+            }
+            StrictJsonRejection::InvalidJsonStructure(message) => {
+                ApiError::bad_request("invalid-json", "The document is invalid")
+                    .with_field_errors(field_errors_from_serde(&message))
+                    .with_detail(message)
+            }
+        }
+    }
+}
+
+impl IntoResponse for StrictJsonRejection {
+    fn into_response(self) -> axum::response::Response {
+        ApiError::from(self).into_response()
+    }
+}
+
+impl<S, T> FromRequest<S, Body> for StrictJson<T>
+where
+    T: DeserializeOwned + Send + 'static,
+    S: Send + Sync,
+{
+    type Rejection = StrictJsonRejection;
+
+    async fn from_request(req: Request<Body>, _state: &S) -> Result<Self, Self::Rejection> {
+        let limits = req
+            .extensions()
+            .get::<StrictFormLimits>()
+            .copied()
+            .unwrap_or_default();
+        let whole: Bytes = to_bytes(req.into_body(), limits.max_body_bytes)
+            .await
+            .map_err(|e| {
+                if e.into_inner().is::<LengthLimitError>() {
+                    StrictJsonRejection::PayloadTooLarge
+                } else {
+                    StrictJsonRejection::ReadBody
+                }
+            })?;
+
+        parse_strict_json(&whole).map(StrictJson)
+    }
+}
+
+/// Run all StrictJson checks on a complete body
+pub fn parse_strict_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, StrictJsonRejection> {
+    let text = std::str::from_utf8(body).map_err(|_| StrictJsonRejection::InvalidUtf8)?;
+
+    // Going through Value first lets us see decoded strings, so escaped
+    // NULs are caught as well as raw ones
+    let UniqueKeys(value) = serde_json::from_str(text).map_err(|e| {
+        if e.is_data() {
+            // A repeated key, reported as serde's "duplicate field `x`"
+            StrictJsonRejection::InvalidJsonStructure(e.to_string())
+        } else {
+            StrictJsonRejection::MalformedJson(e.to_string())
+        }
+    })?;
+    if contains_nul(&value) {
+        return Err(StrictJsonRejection::NulCharacter);
+    }
+
+    let mut unknown = Vec::new();
+    let t: T = serde_ignored::deserialize(value, |path| unknown.push(path.to_string()))
+        .map_err(|e| StrictJsonRejection::InvalidJsonStructure(e.to_string()))?;
+    if !unknown.is_empty() {
+        return Err(StrictJsonRejection::UnknownFields(unknown));
+    }
+
+    Ok(t)
+}
+
+/// A `Value` whose objects never repeat a key. `serde_json` keeps the last
+/// of repeated keys; StrictForm rejects them, and so does this.
+struct UniqueKeys(Value);
+
+impl<'de> Deserialize<'de> for UniqueKeys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer
+            .deserialize_any(UniqueKeysVisitor)
+            .map(UniqueKeys)
+    }
+}
+
+struct UniqueKeysVisitor;
+
+impl<'de> Visitor<'de> for UniqueKeysVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+        // JSON has no NaN or infinity, so this never falls back to null
+        Ok(Number::from_f64(v).map_or(Value::Null, Value::Number))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Value, E> {
+        Ok(Value::String(v))
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut items = Vec::new();
+        while let Some(UniqueKeys(item)) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut object = Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if object.contains_key(&key) {
+                return Err(de::Error::custom(format_args!("duplicate field `{key}`")));
+            }
+            let UniqueKeys(value) = map.next_value()?;
+            object.insert(key, value);
+        }
+        Ok(Value::Object(object))
+    }
+}
+
+fn contains_nul(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s.contains('\0'),
+        Value::Array(items) => items.iter().any(contains_nul),
+        Value::Object(map) => map.iter().any(|(k, v)| k.contains('\0') || contains_nul(v)),
+        Value::Null | Value::Bool(_) | Value::Number(_) => false,
+    }
+}
@@ -0,0 +1,357 @@
+// strict_multipart.rs
+// StrictMultipart extractor for Axum, the multipart/form-data counterpart of StrictForm
+// - requires Content-Type multipart/form-data with a boundary
+// - enforces per-route limits on total size, part count, name, text and file size
+// - text parts must be UTF-8 without NUL bytes and deserialize into T like a form
+// - file parts must have an allowed Content-Type and are streamed to temporary files,
+//   created and removed on blocking threads
+// - reports every bad text field, like StrictForm
+
+use axum::{
+    body::Body,
+    extract::FromRequest,
+    http::{Request, StatusCode, header::CONTENT_TYPE},
+    response::IntoResponse,
+};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::path::Path;
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+
+use crate::error::{ApiError, FieldError};
+use crate::form_deserializer::{FormPair, from_pairs};
+use crate::strict_form::parse_content_type;
+use crate::urlencoded::{UrlencodedError, UrlencodedSource};
+
+pub const MULTIPART_FORM_DATA: &str = "multipart/form-data";
+
+/// Limits applied by `StrictMultipart`, read from the request extensions
+/// like `StrictFormLimits`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StrictMultipartLimits {
+    /// Size of the whole body, boundaries and part headers included
+    pub max_body_bytes: u64,
+    /// Number of parts, text and file together
+    pub max_fields: usize,
+    /// Length of a part name in bytes
+    pub max_key_len: usize,
+    /// Length of a text part in bytes
+    pub max_value_len: usize,
+    /// Size of a single file part
+    pub max_file_bytes: u64,
+    /// Media types accepted for file parts, compared without parameters
+    pub allowed_file_types: Vec<String>,
+}
+
+impl Default for StrictMultipartLimits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 16 * 1024 * 1024, // 16 MiB
+            max_fields: 32,
+            max_key_len: 128,
+            max_value_len: 64 * 1024,
+            max_file_bytes: 8 * 1024 * 1024,
+            allowed_file_types: ["text/csv", "image/png", "image/jpeg", "image/gif"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+/// A file part, already written to a temporary file that is removed on drop
+#[derive(Debug)]
+pub struct UploadedFile {
+    /// Name of the part in the form
+    pub field: String,
+    /// File name as sent by the client; never use it as a path
+    pub file_name: String,
+    /// Media type without parameters, one of `allowed_file_types`
+    pub content_type: String,
+    pub size: u64,
+    /// Only `None` once taken by `into_temp_file` or `Drop`
+    file: Option<NamedTempFile>,
+}
+
+impl UploadedFile {
+    pub fn path(&self) -> &Path {
+        self.temp_file().path()
+    }
+
+    pub fn temp_file(&self) -> &NamedTempFile {
+        self.file.as_ref().expect("present until dropped")
+    }
+
+    /// Take over the temporary file, e.g. to persist it. Dropping the
+    /// result removes the file on the current thread.
+    pub fn into_temp_file(mut self) -> NamedTempFile {
+        self.file.take().expect("present until dropped")
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        let Some(file) = self.file.take() else {
+            return;
+        };
+        // Removing the file is a blocking call; keep it off the runtime
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || drop(file));
+            }
+            Err(_) => drop(file),
+        }
+    }
+}
+
+/// StrictMultipart wrapper — use in handlers as `StrictMultipart<T>`.
+/// `fields` holds the text parts, `files` the file parts in request order.
+pub struct StrictMultipart<T> {
+    pub fields: T,
+    pub files: Vec<UploadedFile>,
+}
+
+#[derive(Debug)]
+pub enum StrictMultipartRejection {
+    UnsupportedMediaType,
+    Malformed(String),
+    PayloadTooLarge,
+    FileTooLarge { field: String, max: u64 },
+    UnsupportedFileType { field: String, content_type: String },
+    Storage(std::io::Error),
+    Invalid(UrlencodedError),
+}
+
+impl From<UrlencodedError> for StrictMultipartRejection {
+    fn from(error: UrlencodedError) -> Self {
+        StrictMultipartRejection::Invalid(error)
+    }
+}
+
+impl From<StrictMultipartRejection> for ApiError {
+    fn from(rejection: StrictMultipartRejection) -> Self {
+        match rejection {
+            StrictMultipartRejection::UnsupportedMediaType => ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported-media-type",
+                "The request body has an unsupported media type",
+            )
+            .with_detail(format!("Expected {MULTIPART_FORM_DATA} with a boundary")),
+            StrictMultipartRejection::Malformed(detail) => ApiError::bad_request(
+                "malformed-multipart",
+                "The multipart body could not be parsed",
+            )
+            .with_detail(detail),
+            StrictMultipartRejection::PayloadTooLarge => ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload-too-large",
+                "The request body is too large",
+            ),
+            StrictMultipartRejection::FileTooLarge { field, max } => ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "file-too-large",
+                "An uploaded file is too large",
+            )
+            .with_field_errors(vec![FieldError::new(
+                field,
+                "too_long",
+                format!("Files may be at most {max} bytes"),
+            )]),
+            StrictMultipartRejection::UnsupportedFileType {
+                field,
+                content_type,
+            } => ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported-file-type",
+                "An uploaded file has an unsupported media type",
+            )
+            .with_field_errors(vec![FieldError::new(
+                field,
+                "unsupported_type",
+                format!("Files of type {content_type} are not accepted"),
+            )]),
+            StrictMultipartRejection::Storage(e) => {
+                tracing::error!("Failed to store an uploaded file: {e}");
+                ApiError::internal()
+            }
+            StrictMultipartRejection::Invalid(error) => {
+                error.into_api_error(UrlencodedSource::Form)
+            }
+        }
+    }
+}
+
+impl IntoResponse for StrictMultipartRejection {
+    fn into_response(self) -> axum::response::Response {
+        ApiError::from(self).into_response()
+    }
+}
+
+impl From<multer::Error> for StrictMultipartRejection {
+    fn from(e: multer::Error) -> Self {
+        match e {
+            multer::Error::StreamSizeExceeded { .. } => StrictMultipartRejection::PayloadTooLarge,
+            e => StrictMultipartRejection::Malformed(e.to_string()),
+        }
+    }
+}
+
+impl<S, T> FromRequest<S, Body> for StrictMultipart<T>
+where
+    T: DeserializeOwned + Send + 'static,
+    S: Send + Sync,
+{
+    type Rejection = StrictMultipartRejection;
+
+    async fn from_request(req: Request<Body>, _state: &S) -> Result<Self, Self::Rejection> {
+        let limits = req
+            .extensions()
+            .get::<StrictMultipartLimits>()
+            .cloned()
+            .unwrap_or_default();
+
+        let is_multipart = parse_content_type(req.headers())
+            .is_some_and(|content_type| content_type.essence == MULTIPART_FORM_DATA);
+        let boundary = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .filter(|_| is_multipart)
+            .and_then(|value| multer::parse_boundary(value).ok())
+            .ok_or(StrictMultipartRejection::UnsupportedMediaType)?;
+
+        let constraints = multer::Constraints::new()
+            .size_limit(multer::SizeLimit::new().whole_stream(limits.max_body_bytes));
+        let mut multipart = multer::Multipart::with_constraints(
+            req.into_body().into_data_stream(),
+            boundary,
+            constraints,
+        );
+
+        let mut pairs: Vec<FormPair<'static>> = Vec::new();
+        let mut files: Vec<UploadedFile> = Vec::new();
+        let mut errors: Vec<FieldError> = Vec::new();
+        let mut count = 0;
+
+        while let Some(field) = multipart.next_field().await? {
+            count += 1;
+            if count > limits.max_fields {
+                return Err(UrlencodedError::TooManyFields(limits.max_fields).into());
+            }
+            let name = field
+                .name()
+                .ok_or_else(|| StrictMultipartRejection::Malformed("A part has no name".into()))?
+                .to_string();
+            if name.len() > limits.max_key_len {
+                return Err(UrlencodedError::KeyTooLong(limits.max_key_len).into());
+            }
+            if name.contains('\0') {
+                return Err(UrlencodedError::InvalidUtf8.into());
+            }
+
+            match field.file_name().map(str::to_string) {
+                Some(file_name) => {
+                    files.push(store_file(field, name, file_name, &limits).await?);
+                }
+                None => match read_text(field, &limits).await? {
+                    Ok(value) => pairs.push((Cow::Owned(name), Cow::Owned(value))),
+                    Err(message) => errors.push(FieldError::new(name, "too_long", message)),
+                },
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(UrlencodedError::InvalidFields(errors).into());
+        }
+        let fields = from_pairs(&pairs).map_err(UrlencodedError::InvalidFields)?;
+        Ok(StrictMultipart { fields, files })
+    }
+}
+
+/// Buffer a text part up to `max_value_len`. An overly long value is an
+/// error for that field only; the rest of it is drained so parsing goes on.
+async fn read_text(
+    mut field: multer::Field<'_>,
+    limits: &StrictMultipartLimits,
+) -> Result<Result<String, String>, StrictMultipartRejection> {
+    let mut value = Vec::new();
+    let mut too_long = false;
+    while let Some(chunk) = field.chunk().await? {
+        if too_long || value.len() + chunk.len() > limits.max_value_len {
+            too_long = true;
+            continue;
+        }
+        value.extend_from_slice(&chunk);
+    }
+    if too_long {
+        return Ok(Err(format!("Value exceeds {} bytes", limits.max_value_len)));
+    }
+
+    let value = String::from_utf8(value).map_err(|_| UrlencodedError::InvalidUtf8)?;
+    if value.contains('\0') {
+        return Err(UrlencodedError::InvalidUtf8.into());
+    }
+    Ok(Ok(value))
+}
+
+/// Check the media type of a file part, then stream it to a temporary file
+async fn store_file(
+    mut field: multer::Field<'_>,
+    name: String,
+    file_name: String,
+    limits: &StrictMultipartLimits,
+) -> Result<UploadedFile, StrictMultipartRejection> {
+    // RFC 7578: a file part without Content-Type is application/octet-stream
+    let content_type = field
+        .content_type()
+        .map(|mime| mime.essence_str().to_ascii_lowercase())
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    if !limits
+        .allowed_file_types
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(&content_type))
+    {
+        return Err(StrictMultipartRejection::UnsupportedFileType {
+            field: name,
+            content_type,
+        });
+    }
+
+    let (file, writer) = tokio::task::spawn_blocking(|| {
+        let file = NamedTempFile::new()?;
+        let writer = file.as_file().try_clone()?;
+        Ok((file, writer))
+    })
+    .await
+    .map_err(|e| StrictMultipartRejection::Storage(std::io::Error::other(e)))?
+    .map_err(StrictMultipartRejection::Storage)?;
+    // From here on, bailing out removes the file through `UploadedFile`'s drop
+    let mut upload = UploadedFile {
+        field: name,
+        file_name,
+        content_type,
+        size: 0,
+        file: Some(file),
+    };
+
+    let mut writer = tokio::fs::File::from_std(writer);
+    while let Some(chunk) = field.chunk().await? {
+        upload.size += chunk.len() as u64;
+        if upload.size > limits.max_file_bytes {
+            return Err(StrictMultipartRejection::FileTooLarge {
+                field: upload.field.clone(),
+                max: limits.max_file_bytes,
+            });
+        }
+        writer
+            .write_all(&chunk)
+            .await
+            .map_err(StrictMultipartRejection::Storage)?;
+    }
+    writer
+        .flush()
+        .await
+        .map_err(StrictMultipartRejection::Storage)?;
+
+    Ok(upload)
+}
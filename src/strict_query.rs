@@ -0,0 +1,72 @@
+// strict_query.rs
+// StrictQuery extractor for Axum, the query string counterpart of StrictForm
+// - applies the same StrictFormLimits, read from the request extensions
+// - rejects query strings longer than max_body_bytes with 414
+// - validates and deserializes with the shared rules in urlencoded.rs
+
+use axum::{
+    extract::FromRequestParts,
+    http::{StatusCode, request::Parts},
+    response::IntoResponse,
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::ApiError;
+use crate::strict_form::StrictFormLimits;
+use crate::urlencoded::{UrlencodedError, UrlencodedSource, from_bytes};
+
+/// StrictQuery wrapper — use in handlers as `StrictQuery<T>`
+pub struct StrictQuery<T>(pub T);
+
+#[derive(Debug)]
+pub enum StrictQueryRejection {
+    UriTooLong,
+    Invalid(UrlencodedError),
+}
+
+impl From<UrlencodedError> for StrictQueryRejection {
+    fn from(error: UrlencodedError) -> Self {
+        StrictQueryRejection::Invalid(error)
+    }
+}
+
+impl From<StrictQueryRejection> for ApiError {
+    fn from(rejection: StrictQueryRejection) -> Self {
+        match rejection {
+            StrictQueryRejection::UriTooLong => ApiError::new(
+                StatusCode::URI_TOO_LONG,
+                "uri-too-long",
+                "The query string is too long",
+            ),
+            StrictQueryRejection::Invalid(error) => error.into_api_error(UrlencodedSource::Query),
+        }
+    }
+}
+
+impl IntoResponse for StrictQueryRejection {
+    fn into_response(self) -> axum::response::Response {
+        ApiError::from(self).into_response()
+    }
+}
+
+impl<S, T> FromRequestParts<S> for StrictQuery<T>
+where
+    T: DeserializeOwned + Send + 'static,
+    S: Send + Sync,
+{
+    type Rejection = StrictQueryRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let limits = parts
+            .extensions
+            .get::<StrictFormLimits>()
+            .copied()
+            .unwrap_or_default();
+
+        let query = parts.uri.query().unwrap_or_default();
+        if query.len() > limits.max_body_bytes {
+            return Err(StrictQueryRejection::UriTooLong);
+        }
+        Ok(StrictQuery(from_bytes(query.as_bytes(), &limits)?))
+    }
+}
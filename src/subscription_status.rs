@@ -0,0 +1,176 @@
+//! src/subscription_status.rs
+//! Lifecycle of a subscriber, backed by the `subscription_status` Postgres enum.
+//!
+//!   pending_confirmation -> confirmed -> unsubscribed | bounced | complained
+//!
+//! A pending subscriber can also drop out straight to one of the terminal
+//! states. Every status change goes through `transition_subscriber`, so the
+//! rules below are the only place that decides what is allowed.
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "subscription_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+    Bounced,
+    Complained,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTransition {
+    pub from: SubscriptionStatus,
+    pub to: SubscriptionStatus,
+}
+
+impl std::fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cannot move subscriber from {} to {}",
+            self.from.as_str(),
+            self.to.as_str()
+        )
+    }
+}
+
+impl SubscriptionStatus {
+    pub const ALL: [SubscriptionStatus; 5] = [
+        SubscriptionStatus::PendingConfirmation,
+        SubscriptionStatus::Confirmed,
+        SubscriptionStatus::Unsubscribed,
+        SubscriptionStatus::Bounced,
+        SubscriptionStatus::Complained,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubscriptionStatus::PendingConfirmation => "pending_confirmation",
+            SubscriptionStatus::Confirmed => "confirmed",
+            SubscriptionStatus::Unsubscribed => "unsubscribed",
+            SubscriptionStatus::Bounced => "bounced",
+            SubscriptionStatus::Complained => "complained",
+        }
+    }
+
+    /// Only confirmed subscribers get newsletters
+    pub fn receives_newsletters(&self) -> bool {
+        *self == SubscriptionStatus::Confirmed
+    }
+
+    /// Unsubscribed, bounced and complained are final
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            SubscriptionStatus::Unsubscribed
+                | SubscriptionStatus::Bounced
+                | SubscriptionStatus::Complained
+        )
+    }
+
+    /// Validate a move to `next`. Staying in the same state is a no-op and
+    /// always allowed, so repeated clicks on the same link are harmless.
+    pub fn transition_to(
+        self,
+        next: SubscriptionStatus,
+    ) -> Result<SubscriptionStatus, InvalidTransition> {
+        use SubscriptionStatus::*;
+
+        let allowed = self == next
+            || match self {
+                PendingConfirmation => true,
+                Confirmed => next.is_terminal(),
+                Unsubscribed | Bounced | Complained => false,
+            };
+
+        if allowed {
+            Ok(next)
+        } else {
+            Err(InvalidTransition {
+                from: self,
+                to: next,
+            })
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TransitionError {
+    SubscriberNotFound,
+    Invalid(InvalidTransition),
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for TransitionError {
+    fn from(e: sqlx::Error) -> Self {
+        TransitionError::Database(e)
+    }
+}
+
+impl From<TransitionError> for ApiError {
+    fn from(e: TransitionError) -> Self {
+        match e {
+            TransitionError::SubscriberNotFound => ApiError::new(
+                StatusCode::NOT_FOUND,
+                "subscriber-not-found",
+                "The subscriber does not exist",
+            ),
+            TransitionError::Invalid(e) => ApiError::new(
+                StatusCode::CONFLICT,
+                "invalid-transition",
+                "The subscription can't change to that status",
+            )
+            .with_detail(e.to_string()),
+            TransitionError::Database(e) => {
+                tracing::error!(error = %e, "Failed to change subscription status");
+                ApiError::internal()
+            }
+        }
+    }
+}
+
+/// Move a subscriber to `next`, locking the row so concurrent updates
+/// can't slip an invalid transition in between the check and the write.
+/// Call it inside a transaction. Returns the status the subscriber had before.
+pub async fn transition_subscriber(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    next: SubscriptionStatus,
+) -> Result<SubscriptionStatus, TransitionError> {
+    let current = sqlx::query!(
+        r#"
+            SELECT status AS "status: SubscriptionStatus"
+            FROM subscriptions
+            WHERE id = $1
+            FOR UPDATE
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(&mut *connection)
+    .await?
+    .ok_or(TransitionError::SubscriberNotFound)?
+    .status;
+
+    let next = current
+        .transition_to(next)
+        .map_err(TransitionError::Invalid)?;
+
+    if next != current {
+        sqlx::query!(
+            r#"UPDATE subscriptions SET status = $1 WHERE id = $2"#,
+            next as SubscriptionStatus,
+            subscriber_id
+        )
+        .execute(&mut *connection)
+        .await?;
+    }
+
+    Ok(current)
+}
@@ -0,0 +1,183 @@
+// urlencoded.rs
+// Strict application/x-www-form-urlencoded parsing shared by StrictForm and StrictQuery
+// - rejects invalid percent-encoding
+// - rejects invalid (non-UTF-8) decoded fields and NUL bytes
+// - enforces the field count, key and value length limits
+// - rejects repeated keys unless the target field is a sequence like Vec<T>
+// - validates and decodes in a single pass, borrowing from the input where it can
+// - deserializes into T after strict validation, reporting every bad field
+
+use axum::http::StatusCode;
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+
+use crate::error::{ApiError, FieldError};
+use crate::form_deserializer::{FormPair, from_pairs};
+use crate::strict_form::StrictFormLimits;
+
+/// Why urlencoded input was refused, whatever part of the request it came from
+#[derive(Debug)]
+pub enum UrlencodedError {
+    InvalidPercentEncoding,
+    InvalidUtf8,
+    TooManyFields(usize),
+    KeyTooLong(usize),
+    InvalidFields(Vec<FieldError>),
+}
+
+/// Where the urlencoded input came from, for error messages
+#[derive(Debug, Clone, Copy)]
+pub enum UrlencodedSource {
+    Form,
+    Query,
+}
+
+impl UrlencodedSource {
+    fn noun(self) -> &'static str {
+        match self {
+            UrlencodedSource::Form => "form",
+            UrlencodedSource::Query => "query string",
+        }
+    }
+}
+
+impl UrlencodedError {
+    pub fn into_api_error(self, source: UrlencodedSource) -> ApiError {
+        let noun = source.noun();
+        match self {
+            UrlencodedError::InvalidPercentEncoding => ApiError::bad_request(
+                "invalid-percent-encoding",
+                format!("The {noun} contains an invalid percent-encoded sequence"),
+            ),
+            UrlencodedError::InvalidUtf8 => ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid-utf8",
+                format!("The {noun} contains invalid UTF-8 or a NUL byte"),
+            ),
+            UrlencodedError::TooManyFields(max) => {
+                ApiError::bad_request("too-many-fields", format!("The {noun} has too many fields"))
+                    .with_detail(format!("At most {max} fields are accepted"))
+            }
+            UrlencodedError::KeyTooLong(max) => ApiError::bad_request(
+                "key-too-long",
+                format!("The {noun} has an overly long field name"),
+            )
+            .with_detail(format!("Field names may be at most {max} bytes long")),
+            UrlencodedError::InvalidFields(errors) => ApiError::bad_request(
+                match source {
+                    UrlencodedSource::Form => "invalid-form",
+                    UrlencodedSource::Query => "invalid-query",
+                },
+                format!("The {noun} data is invalid"),
+            )
+            .with_field_errors(errors),
+        }
+    }
+}
+
+/// Validate and deserialize a complete urlencoded body or query string
+pub fn from_bytes<T: DeserializeOwned>(
+    body: &[u8],
+    limits: &StrictFormLimits,
+) -> Result<T, UrlencodedError> {
+    let pairs = parse_pairs(body, limits)?;
+    from_pairs(&pairs).map_err(UrlencodedError::InvalidFields)
+}
+
+/// Split, validate and decode the input in one pass. Components without
+/// escapes are borrowed from `body`; only the others are copied.
+pub fn parse_pairs<'a>(
+    body: &'a [u8],
+    limits: &StrictFormLimits,
+) -> Result<Vec<FormPair<'a>>, UrlencodedError> {
+    let mut pairs: Vec<FormPair<'a>> = Vec::new();
+    let mut too_long: Vec<FieldError> = Vec::new();
+
+    for pair in body.split(|&b| b == b'&') {
+        if pair.is_empty() {
+            continue;
+        }
+        if pairs.len() + too_long.len() == limits.max_fields {
+            return Err(UrlencodedError::TooManyFields(limits.max_fields));
+        }
+
+        let (raw_key, raw_value) = match pair.iter().position(|&b| b == b'=') {
+            Some(idx) => (&pair[..idx], &pair[idx + 1..]),
+            None => (pair, &pair[pair.len()..]),
+        };
+        let key = decode_component(raw_key)?;
+        if key.len() > limits.max_key_len {
+            return Err(UrlencodedError::KeyTooLong(limits.max_key_len));
+        }
+        let value = decode_component(raw_value)?;
+        if value.len() > limits.max_value_len {
+            too_long.push(FieldError::new(
+                key,
+                "too_long",
+                format!("Value exceeds {} bytes", limits.max_value_len),
+            ));
+            continue;
+        }
+        pairs.push((key, value));
+    }
+
+    if !too_long.is_empty() {
+        return Err(UrlencodedError::InvalidFields(too_long));
+    }
+    Ok(pairs)
+}
+
+/// Percent-decode one key or value, rejecting bad escapes, NUL bytes and
+/// invalid UTF-8. The copy is only made once the first escape shows up.
+fn decode_component(raw: &[u8]) -> Result<Cow<'_, str>, UrlencodedError> {
+    let special = |b: &u8| matches!(b, b'%' | b'+' | 0);
+    let Some(first) = raw.iter().position(special) else {
+        return std::str::from_utf8(raw)
+            .map(Cow::Borrowed)
+            .map_err(|_| UrlencodedError::InvalidUtf8);
+    };
+
+    let mut out = Vec::with_capacity(raw.len());
+    out.extend_from_slice(&raw[..first]);
+    let mut i = first;
+    while i < raw.len() {
+        match raw[i] {
+            b'%' => match (
+                raw.get(i + 1).copied().and_then(from_hex),
+                raw.get(i + 2).copied().and_then(from_hex),
+            ) {
+                (Some(0), Some(0)) => return Err(UrlencodedError::InvalidUtf8),
+                (Some(h), Some(l)) => {
+                    out.push(h * 16 + l);
+                    i += 3;
+                }
+                _ => return Err(UrlencodedError::InvalidPercentEncoding),
+            },
+            b'+' => {
+                out.push(b' ');
+                i += 1;
+            }
+            0 => return Err(UrlencodedError::InvalidUtf8),
+            _ => {
+                // Copy the plain run up to the next escape in one go
+                let run = raw[i..].iter().position(special).unwrap_or(raw.len() - i);
+                out.extend_from_slice(&raw[i..i + run]);
+                i += run;
+            }
+        }
+    }
+
+    String::from_utf8(out)
+        .map(Cow::Owned)
+        .map_err(|_| UrlencodedError::InvalidUtf8)
+}
+
+#[inline]
+fn from_hex(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}